@@ -10,6 +10,7 @@ const form = document.querySelector("form.form-container");
 const input = form.querySelector("input[name='message']");
 
 let clientID = 0;
+let eventSource = null; // read-only live updates when the WebSocket cannot be opened
 
 function exit() {
     if (socket.readyState === WebSocket.OPEN) {
@@ -150,6 +151,11 @@ socket.addEventListener("error", (event) => {
 });
 
 socket.addEventListener("close", () => {
+    // no id was received, the upgrade may have been stripped by a proxy
+    if (clientID === 0 && typeof EventSource !== "undefined") {
+        startEventStream();
+        return;
+    }
     setTimeout(() => {
         console.log("WebSocket is closed now.");
         const userCountDiv = document.getElementById("userCount");
@@ -157,6 +163,45 @@ socket.addEventListener("close", () => {
     }, 500);
 });
 
+// same messages as the WebSocket, the browser reconnects and resumes on its own
+function startEventStream() {
+    if (eventSource !== null) {
+        return;
+    }
+    console.log("Falling back to Server-Sent Events.");
+    eventSource = new EventSource("/events");
+
+    eventSource.addEventListener("message", (event) => {
+        try {
+            const message = JSON.parse(event.data);
+            const userCountDiv = document.getElementById("userCount");
+
+            switch (message.type) {
+                case "message":
+                    appendMessage(message.content, "white", "other");
+                    break;
+
+                case "nbusers":
+                    userCountDiv.textContent = `Connected users: ${message.content} (read-only)`;
+                    break;
+
+                default:
+                    console.warn("Unknown message type received:", message.type);
+                    break;
+            }
+        } catch (e) {
+            console.error("Failed to parse event:", event.data, e);
+        }
+    });
+
+    eventSource.addEventListener("error", () => {
+        if (eventSource.readyState === EventSource.CLOSED) {
+            const userCountDiv = document.getElementById("userCount");
+            userCountDiv.textContent = `You are offline`;
+        }
+    });
+}
+
 function showNotification(message, duration = 5000) {
     const notif = document.getElementById('notification');
     notif.textContent = message;
@@ -169,6 +214,11 @@ function showNotification(message, duration = 5000) {
 form.addEventListener("submit", (e) => {
     e.preventDefault();
 
+    if (eventSource !== null) {
+        showNotification("Live updates only, messages cannot be sent without a WebSocket.");
+        return;
+    }
+
     if (clientID == 0) {
         showNotification("Client ID not received yet. Please wait or reload the page.");
         return;
@@ -1,121 +1,377 @@
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write as _;
 use std::fs;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-macro_rules! names_vec {
-    ( $( ($a:expr, $b:expr, $c:expr) ),* $(,)? ) => {
-        vec![
-            $( ($a.into(), $b.into(), $c.into()) ),*
-        ]
-    };
-}
+#[allow(dead_code)]
+#[path = "src/manifest.rs"]
+mod manifest;
+
+#[allow(dead_code)]
+#[path = "src/content.rs"]
+mod content;
 
 const HASH_LENGTH: usize = 8;
 
+const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const CACHE_DAY: &str = "public, max-age=86400";
+
+// crawlers look for these at their own path, they are not served under a hashed url
+// (sitemap.xml is generated by the server from the content pages)
+const FIXED_PATHS: &[&str] = &["robots.txt"];
+
+// an asset once its bytes are final
+struct Built {
+    name: String,
+    url: String,
+    hash: String,
+    integrity: String,
+    path: PathBuf, // file embedded with include_bytes!
+}
+
+impl Built {
+    fn asset_ref(&self) -> manifest::AssetRef {
+        manifest::AssetRef {
+            url: self.url.clone(),
+            integrity: self.integrity.clone(),
+        }
+    }
+}
+
+// include_bytes! of `path`, or None
+type Variant = Option<PathBuf>;
+
 fn main() {
-    // Associate env_name with path name and URL
-    let names: Vec<(String, String, String)> = names_vec![
-        ("BUILD_URL_JS", "templates/script.js", "/{hash}.js"),
-        ("BUILD_URL_CSS", "templates/styles.css", "/{hash}.css"),
-        ("BUILD_URL_ICON", "templates/favicon.png", "/{hash}.png"),
-        ("BUILD_URL_BG", "templates/bg.webp", "/{hash}.webp"),
-        ("BUILD_VERSION", "", env!("CARGO_PKG_VERSION")),
-    ];
-    let replace_files = ["index.html", "styles.css"];
+    let assets_dir = Path::new(manifest::ASSETS_DIR);
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let templates = ["index.html", "error.html", "layout.html"];
+
+    println!("cargo:rerun-if-changed={}", assets_dir.display());
+    println!("cargo:rerun-if-changed=src/manifest.rs");
+
+    let files = manifest::walk(assets_dir).unwrap_or_else(|e| {
+        println!(
+            "cargo:warning=[ERROR] Failed to read {}: {e}",
+            assets_dir.display()
+        );
+        std::process::exit(1);
+    });
 
     //**** HASHING FOR URL CACHE-BUSTING ****//
-    let names: Vec<(String, String, String)> = names
+    // plain files first, then templated ones which may refer to them (but not to each other)
+    let mut built: Vec<Built> = Vec::new();
+    let mut templated: Vec<(String, PathBuf, String)> = Vec::new();
+    for (name, path) in files {
+        let bytes = read_or_exit(&path);
+        if manifest::is_templated(&bytes) {
+            templated.push((name, path, String::from_utf8_lossy(&bytes).into_owned()));
+            continue;
+        }
+        let hash = hash_bytes(&bytes);
+        let url = url(&name, &hash);
+        let integrity = manifest::integrity(&bytes);
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        built.push(Built {
+            name,
+            url,
+            hash,
+            integrity,
+            path,
+        });
+    }
+
+    let plain: HashMap<String, manifest::AssetRef> = built
         .iter()
-        .map(|(env_var, file, url)| {
-            if file.is_empty() {
-                return (env_var.to_string(), file.to_string(), url.to_string());
-            }
-
-            let file_path = Path::new(file);
-            if !file_path.exists() {
-                println!("cargo:warning=[ERROR] File not found: {file}");
-                std::process::exit(1);
-            }
-
-            match compute_file_hash(file_path) {
-                Ok(hash) => {
-                    let new_url = url.replace("{hash}", &hash);
-                    (env_var.to_string(), file.to_string(), new_url)
-                }
-                Err(e) => {
-                    println!("cargo:warning=[ERROR] Failed to hash {file}: {e}");
-                    std::process::exit(1);
-                }
-            }
-        })
+        .map(|b| (b.name.clone(), b.asset_ref()))
         .collect();
 
-    // Pass env variables to the build script
-    for (env_var, file, url) in &names {
-        if file.is_empty() {
-            println!("cargo:warning=[INFO] {env_var} -> {url}");
-            continue;
-        }
+    for (name, path, contents) in templated {
+        let contents = substitute_or_exit(&contents, &path, &plain);
+        let hash = hash_bytes(contents.as_bytes());
+        let url = url(&name, &hash);
+        let integrity = manifest::integrity(contents.as_bytes());
+        let out_path = out_dir.join(manifest::ASSETS_DIR).join(&name);
+        write_or_exit(&out_path, &contents);
+        built.push(Built {
+            name,
+            url,
+            hash,
+            integrity,
+            path: out_path,
+        });
+    }
+    built.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for asset in &built {
+        println!("cargo:warning=[INFO] {} -> {}", asset.name, asset.url);
+    }
+    println!(
+        "cargo:warning=[INFO] BUILD_VERSION -> {}",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    // Last-Modified of the embedded assets, SOURCE_DATE_EPOCH for reproducible builds
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let build_time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={build_time}");
 
-        println!("cargo:warning=[INFO] {env_var}: ({file}) -> {url}");
-        println!("cargo:rustc-env={env_var}={url}");
-        println!("cargo:rerun-if-changed={file}");
+    //**** MANIFEST OF THE EMBEDDED ASSETS ****//
+    let mut code = String::from("// generated by build.rs from the assets directory\n");
+    code.push_str("pub static MANIFEST: &[ManifestEntry] = &[\n");
+    for asset in &built {
+        let content_type = manifest::content_type(&asset.name);
+        let (br, gzip) = if is_compressible(content_type) {
+            precompress(asset, &out_dir)
+        } else {
+            (None, None)
+        };
+        let _ = writeln!(
+            code,
+            "    ManifestEntry {{ name: {:?}, url: {:?}, hash: {:?}, integrity: {:?}, content_type: {:?}, cache_control: {:?}, bytes: include_bytes!({:?}), br: {}, gzip: {} }},",
+            asset.name,
+            asset.url,
+            asset.hash,
+            asset.integrity,
+            content_type,
+            cache_control(&asset.name),
+            asset.path.display().to_string(),
+            variant_code(&br),
+            variant_code(&gzip),
+        );
     }
+    code.push_str("];\n");
+    write_or_exit(&out_dir.join("manifest.rs"), &code);
 
-    //**** REPLACE CONSTANTS IN HTML/CSS FILES ****//
+    //**** REPLACE CONSTANTS IN TEMPLATES ****//
+    let all: HashMap<String, manifest::AssetRef> = built
+        .iter()
+        .map(|b| (b.name.clone(), b.asset_ref()))
+        .collect();
+    // the compiled templates serve both modes, they look the urls up in the store when
+    // rendered. A name that is not in assets/ still fails the build
+    let lookups: HashMap<String, manifest::AssetRef> = built
+        .iter()
+        .map(|b| {
+            let asset_ref = manifest::AssetRef {
+                url: format!("<%- asset_url({:?})? %>", b.name),
+                integrity: format!("<%- asset_integrity({:?})? %>", b.name),
+            };
+            (b.name.clone(), asset_ref)
+        })
+        .collect();
     let input_base = Path::new("templates");
     let output_base = Path::new("target").join("user_dir");
+    let mut substituted: HashMap<&str, String> = HashMap::new();
 
-    for file in &replace_files {
+    for file in &templates {
         let file_path_in = input_base.join(file);
         let file_path_out = output_base.join(file);
 
         println!("cargo:rerun-if-changed={}", file_path_in.display());
 
-        match replace_constants_in_file(&file_path_in, &file_path_out, &names) {
-            Ok(_) => {
-                println!(
-                    "cargo:warning=[INFO] Successfully replaced constants in {}",
-                    file_path_in.display()
-                );
-            }
-            Err(e) => {
-                println!(
-                    "cargo:warning=[ERROR] Failed to replace constants in {}: {}",
-                    file_path_in.display(),
-                    e
-                );
-                std::process::exit(1);
-            }
+        let contents = String::from_utf8_lossy(&read_or_exit(&file_path_in)).into_owned();
+        write_or_exit(
+            &file_path_out,
+            substitute_or_exit(&contents, &file_path_in, &lookups),
+        );
+        // the urls of the embedded assets, part of the hash of the pages
+        let contents = substitute_or_exit(&contents, &file_path_in, &all);
+        println!(
+            "cargo:warning=[INFO] Successfully replaced constants in {}",
+            file_path_in.display()
+        );
+        substituted.insert(file, contents);
+    }
+
+    //**** MARKDOWN CONTENT PAGES ****//
+    let content_dir = Path::new(content::CONTENT_DIR);
+    println!("cargo:rerun-if-changed={}", content_dir.display());
+    let sources = if content_dir.exists() {
+        manifest::walk(content_dir).unwrap_or_else(|e| {
+            println!(
+                "cargo:warning=[ERROR] Failed to read {}: {e}",
+                content_dir.display()
+            );
+            std::process::exit(1);
+        })
+    } else {
+        Vec::new()
+    };
+
+    let layout = &substituted["layout.html"];
+    let mut code = String::from("// generated by build.rs from the content directory\n");
+    code.push_str("pub static PAGES: &[PageEntry] = &[\n");
+    for (name, path) in sources.iter().filter(|(name, _)| content::is_page(name)) {
+        let source = String::from_utf8_lossy(&read_or_exit(path)).into_owned();
+        let source = substitute_or_exit(&source, path, &all);
+        let page = content::compile(name, &source, layout, build_time).unwrap_or_else(|e| {
+            println!(
+                "cargo:warning=[ERROR] Failed to compile {}: {e}",
+                path.display()
+            );
+            std::process::exit(1);
+        });
+        let out_path = out_dir
+            .join(content::CONTENT_DIR)
+            .join(format!("{name}.html"));
+        write_or_exit(&out_path, &page.html);
+        if page.draft {
+            println!("cargo:warning=[INFO] {name} -> draft, not embedded");
+            continue;
+        }
+        println!("cargo:warning=[INFO] {name} -> {}", page.path);
+        let _ = writeln!(
+            code,
+            "    PageEntry {{ path: {:?}, title: {:?}, description: {:?}, date: {:?}, lastmod: {:?}, tags: &{:?}, html: include_str!({:?}), hash: {:?} }},",
+            page.path,
+            page.title,
+            page.description,
+            page.date,
+            page.lastmod,
+            page.tags,
+            out_path.display().to_string(),
+            page.hash,
+        );
+    }
+    code.push_str("];\n");
+    write_or_exit(&out_dir.join("pages.rs"), &code);
+}
+
+// writes the Brotli and gzip variants of an asset next to the generated files,
+// a variant that is not smaller than the original is not worth a lookup
+fn precompress(asset: &Built, out_dir: &Path) -> (Variant, Variant) {
+    let bytes = read_or_exit(&asset.path);
+    let base = out_dir.join("compressed").join(&asset.name);
+
+    let mut br = Vec::new();
+    let params = brotli::enc::BrotliEncoderParams {
+        quality: 11,
+        ..Default::default()
+    };
+    if let Err(e) = brotli::BrotliCompress(&mut bytes.as_slice(), &mut br, &params) {
+        println!(
+            "cargo:warning=[ERROR] Failed to compress {}: {e}",
+            asset.name
+        );
+        std::process::exit(1);
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    let gzip = encoder
+        .write_all(&bytes)
+        .and_then(|_| encoder.finish())
+        .unwrap_or_else(|e| {
+            println!(
+                "cargo:warning=[ERROR] Failed to compress {}: {e}",
+                asset.name
+            );
+            std::process::exit(1);
+        });
+
+    let keep = |variant: Vec<u8>, ext: &str| -> Variant {
+        if variant.len() >= bytes.len() {
+            return None;
         }
+        let path = PathBuf::from(format!("{}.{ext}", base.display()));
+        write_or_exit(&path, &variant);
+        println!(
+            "cargo:warning=[INFO] {} -> {ext} {} bytes ({} bytes)",
+            asset.name,
+            variant.len(),
+            bytes.len()
+        );
+        Some(path)
+    };
+    (keep(br, "br"), keep(gzip, "gz"))
+}
+
+// text formats gain from compression, images and fonts are already compressed
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.starts_with("application/javascript")
+        || content_type.starts_with("application/json")
+        || content_type.starts_with("application/xml")
+        || content_type.starts_with("image/svg+xml")
+}
+
+fn variant_code(variant: &Variant) -> String {
+    match variant {
+        Some(path) => format!("Some(include_bytes!({:?}))", path.display().to_string()),
+        None => "None".to_string(),
     }
 }
 
-fn replace_constants_in_file(
-    file_path_in: &Path,
-    file_path_out: &Path,
-    names: &[(String, String, String)],
-) -> Result<(), std::io::Error> {
-    let contents = fs::read_to_string(file_path_in)?;
-    let mut new_contents = contents;
+fn read_or_exit(path: &Path) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| {
+        println!(
+            "cargo:warning=[ERROR] Failed to read {}: {e}",
+            path.display()
+        );
+        std::process::exit(1);
+    })
+}
 
-    for (env_var, _, url) in names {
-        new_contents = new_contents.replace(&format!("{{{{{env_var}}}}}"), url);
+fn write_or_exit(path: &Path, contents: impl AsRef<[u8]>) {
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(path, contents));
+    if let Err(e) = result {
+        println!(
+            "cargo:warning=[ERROR] Failed to write {}: {e}",
+            path.display()
+        );
+        std::process::exit(1);
     }
+}
 
-    if let Some(parent) = file_path_out.parent() {
-        fs::create_dir_all(parent)?;
+fn substitute_or_exit(
+    contents: &str,
+    path: &Path,
+    refs: &HashMap<String, manifest::AssetRef>,
+) -> String {
+    manifest::substitute(contents, |name| refs.get(name).cloned()).unwrap_or_else(|e| {
+        println!(
+            "cargo:warning=[ERROR] Failed to replace constants in {}: {e}",
+            path.display()
+        );
+        std::process::exit(1);
+    })
+}
+
+// hashed urls are cache busted when the content changes, fixed ones are not
+fn url(name: &str, hash: &str) -> String {
+    match name.rsplit_once('.') {
+        _ if FIXED_PATHS.contains(&name) => format!("/{name}"),
+        Some((_, ext)) => format!("/{}.{ext}", &hash[..HASH_LENGTH]),
+        None => format!("/{}", &hash[..HASH_LENGTH]),
     }
+}
 
-    fs::write(file_path_out, new_contents)?;
-    Ok(())
+fn cache_control(name: &str) -> &'static str {
+    if FIXED_PATHS.contains(&name) {
+        CACHE_DAY
+    } else {
+        CACHE_IMMUTABLE
+    }
 }
 
-/// Computes the SHA-256 hash of a file and returns the first 8 characters of the hex digest.
-fn compute_file_hash<P: AsRef<Path>>(path: P) -> Result<String, std::io::Error> {
-    let bytes = fs::read(path)?;
+/// Computes the SHA-256 hash of some bytes and returns the hex digest.
+fn hash_bytes(bytes: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(bytes);
-    let result = hasher.finalize();
-    Ok(format!("{result:x}")[..HASH_LENGTH].to_string())
+    format!("{:x}", hasher.finalize())
 }
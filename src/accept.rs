@@ -0,0 +1,45 @@
+/*  Accept-style headers (RFC 9110, 12.4.2)
+    - Accept and Accept-Encoding are comma separated lists of values with an optional
+      q-value, each caller decides how a value matches what it offers
+*/
+
+// the values of an Accept-style header with their q-value, 1 when absent and clamped to
+// [0, 1] (an invalid one counts as absent)
+pub fn items(header: &str) -> impl Iterator<Item = (&str, f32)> {
+    header.split(',').filter_map(|item| {
+        let mut parts = item.split(';');
+        let value = parts.next().unwrap_or("").trim();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0)
+            .clamp(0.0, 1.0);
+        (!value.is_empty()).then_some((value, q))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(header: &str) -> Vec<(&str, f32)> {
+        items(header).collect()
+    }
+
+    #[test]
+    fn values_and_q_values() {
+        assert_eq!(
+            parsed("text/html, application/json;q=0.9 , */*; q=0.1"),
+            [("text/html", 1.0), ("application/json", 0.9), ("*/*", 0.1)]
+        );
+        assert_eq!(parsed("text/html;level=1;q=0.5"), [("text/html", 0.5)]);
+        assert_eq!(parsed("gzip;q=2, br;q=-1"), [("gzip", 1.0), ("br", 0.0)]);
+        assert_eq!(parsed("gzip;q=abc"), [("gzip", 1.0)]);
+    }
+
+    #[test]
+    fn empty_items_are_skipped() {
+        assert!(parsed("").is_empty());
+        assert_eq!(parsed(" , gzip,,"), [("gzip", 1.0)]);
+    }
+}
@@ -0,0 +1,192 @@
+/*  Connection activity
+    - Tracks the requests in flight on a connection and when the last one ended,
+      a connection without a request for idle_timeout is shut down gracefully
+    - hyper arms its HTTP/1 header timer as soon as it waits for the next request, so on its
+      own header_read_timeout would also close idle keep-alive connections. HeaderTimer only
+      starts counting once the first byte of the request is read, idle time is left to idle()
+*/
+use hyper::rt::{Sleep, Timer};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time;
+
+pub struct Activity {
+    in_flight: AtomicUsize,
+    last: Mutex<Instant>, // end of the last request, or start of the connection
+    first_byte: Mutex<Option<Instant>>, // first byte read while no request was in flight
+    header_waker: Mutex<Option<Waker>>,
+}
+
+// marks a request in flight until dropped, even if the client goes away mid-request
+pub struct InFlight(Arc<Activity>);
+
+impl Activity {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            in_flight: AtomicUsize::new(0),
+            last: Mutex::new(Instant::now()),
+            first_byte: Mutex::new(None),
+            header_waker: Mutex::new(None),
+        })
+    }
+
+    pub fn start(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        *self.first_byte.lock().unwrap() = None;
+        InFlight(self.clone())
+    }
+
+    // resolves once no request has been in flight for `timeout`
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let since = if self.in_flight.load(Ordering::Relaxed) > 0 {
+                Instant::now()
+            } else {
+                *self.last.lock().unwrap()
+            };
+            let deadline = since + timeout;
+            if deadline <= Instant::now() {
+                return;
+            }
+            time::sleep_until(deadline.into()).await;
+        }
+    }
+
+    fn read(&self) {
+        let mut first_byte = self.first_byte.lock().unwrap();
+        if first_byte.is_none() {
+            *first_byte = Some(Instant::now());
+            if let Some(waker) = self.header_waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        *self.0.last.lock().unwrap() = Instant::now();
+        *self.0.first_byte.lock().unwrap() = None;
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// the connection IO, reporting reads to the activity of the connection
+pub struct TrackedIo<I> {
+    inner: I,
+    activity: Arc<Activity>,
+}
+
+impl<I> TrackedIo<I> {
+    pub fn new(inner: I, activity: Arc<Activity>) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for TrackedIo<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > before {
+            self.activity.read();
+        }
+        result
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for TrackedIo<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// timer of the HTTP/1 header read timeout, hyper uses it for nothing else
+pub struct HeaderTimer(Arc<Activity>);
+
+impl HeaderTimer {
+    pub fn new(activity: Arc<Activity>) -> Self {
+        Self(activity)
+    }
+}
+
+impl Timer for HeaderTimer {
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Sleep>> {
+        Box::pin(HeaderSleep {
+            activity: self.0.clone(),
+            timeout: duration,
+            sleep: None,
+        })
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Sleep>> {
+        self.sleep(deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+// completes `timeout` after the first byte of the request head was read, the bytes read
+// between requests are the next head (those of the first one may be read by the protocol
+// detection before hyper arms the timer)
+struct HeaderSleep {
+    activity: Arc<Activity>,
+    timeout: Duration,
+    sleep: Option<Pin<Box<time::Sleep>>>,
+}
+
+impl Future for HeaderSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.sleep.is_none() {
+            // the waker is stored under the first_byte lock so a read cannot be missed
+            let first_byte = self.activity.first_byte.lock().unwrap();
+            match *first_byte {
+                Some(at) => {
+                    drop(first_byte);
+                    let deadline = at + self.timeout;
+                    self.sleep = Some(Box::pin(time::sleep_until(deadline.into())));
+                }
+                None => {
+                    *self.activity.header_waker.lock().unwrap() = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+        self.sleep.as_mut().unwrap().as_mut().poll(cx)
+    }
+}
+
+impl Sleep for HeaderSleep {}
@@ -0,0 +1,362 @@
+use crate::config;
+use crate::constants;
+use crate::crypt;
+use crate::db;
+use crate::handler::RequestBody;
+use crate::log::AUDIT_TARGET;
+use crate::middleware;
+use crate::ratelimit::RateLimiter;
+use crate::router::HandlerResult;
+use crate::ws;
+
+use base64::{Engine as _, engine::general_purpose};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::header::{ALLOW, RETRY_AFTER};
+use hyper::http::response::Builder;
+use hyper::{Method, StatusCode};
+use hyper::{Request, Response};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+
+use tracing::{info, warn};
+
+// failed attempts per client IP, a client out of tokens is refused before any verification
+static AUTH_FAILURES: Lazy<RateLimiter> = Lazy::new(|| {
+    RateLimiter::new(
+        constants::ADMIN_AUTH_FAILURES_PER_SECOND,
+        constants::ADMIN_AUTH_FAILURES_BURST,
+    )
+});
+
+// argon2 verifications running at once, each one takes a blocking thread and its memory
+static VERIFICATIONS: Semaphore = Semaphore::const_new(constants::ADMIN_MAX_VERIFICATIONS);
+
+enum Auth {
+    Granted,
+    Denied,
+    Busy, // too many verifications running
+}
+
+// every admin response is logged to the audit log with the outcome
+macro_rules! audit {
+    ($ip:expr, $method:expr, $path:expr, $status:expr, $detail:expr) => {
+        info!(
+            target: AUDIT_TARGET,
+            ip = %$ip,
+            method = %$method,
+            path = %$path,
+            status = $status.as_u16(),
+            "{}",
+            $detail
+        )
+    };
+}
+
+fn json_response<T: Serialize>(
+    response_builder: Builder,
+    status: StatusCode,
+    value: &T,
+) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+    response_builder
+        .status(status)
+        .header("Cache-Control", "no-store")
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+fn error_response(
+    response_builder: Builder,
+    status: StatusCode,
+    msg: &str,
+) -> Response<Full<Bytes>> {
+    json_response(
+        response_builder,
+        status,
+        &serde_json::json!({ "error": msg }),
+    )
+}
+
+// checks the Authorization header against the configured token or password hash
+async fn authorize(headers: &hyper::HeaderMap) -> Auth {
+    let granted = |ok: bool| if ok { Auth::Granted } else { Auth::Denied };
+    let cfg = &config::get().admin;
+    let Some(auth) = headers.get("Authorization").and_then(|v| v.to_str().ok()) else {
+        return Auth::Denied;
+    };
+
+    if let (Some(token), Some(given)) = (&cfg.token, auth.strip_prefix("Bearer ")) {
+        return granted(crypt::constant_time_eq(
+            token.as_bytes(),
+            given.trim().as_bytes(),
+        ));
+    }
+
+    if let (Some(hash), Some(given)) = (&cfg.password_hash, auth.strip_prefix("Basic ")) {
+        let Some((user, password)) = general_purpose::STANDARD
+            .decode(given.trim())
+            .ok()
+            .and_then(|b| String::from_utf8(b).ok())
+            .and_then(|s| {
+                s.split_once(':')
+                    .map(|(u, p)| (u.to_string(), p.to_string()))
+            })
+        else {
+            return Auth::Denied;
+        };
+        if !crypt::constant_time_eq(cfg.username.as_bytes(), user.as_bytes()) {
+            return Auth::Denied;
+        }
+
+        // argon2 is deliberately slow, keep it off the async workers, the permit goes with
+        // the verification even if the client leaves before it ends
+        let Ok(permit) = VERIFICATIONS.try_acquire() else {
+            return Auth::Busy;
+        };
+        let hash = hash.clone();
+        let verified = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            crypt::verify_password(&password, &hash)
+        });
+        return granted(verified.await.unwrap_or(false));
+    }
+
+    Auth::Denied
+}
+
+// methods of the admin route at `segments`, for 405 and OPTIONS, HEAD is served as GET
+fn allowed_methods(segments: &[&str]) -> Option<&'static str> {
+    match segments {
+        ["users"] | ["messages"] | ["config"] => Some("GET, HEAD, OPTIONS"),
+        ["messages", _] => Some("DELETE, OPTIONS"),
+        ["announce"] | ["db", "flush"] | ["db", "compact"] => Some("POST, OPTIONS"),
+        _ => None,
+    }
+}
+
+pub async fn handle_admin(req: Request<RequestBody>) -> HandlerResult {
+    let ip = middleware::client_ip(&req);
+    let response_builder = Response::builder();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+
+    // without credentials in the config the admin API does not exist
+    if !config::get().admin.enabled() {
+        return Ok(error_response(
+            response_builder,
+            StatusCode::NOT_FOUND,
+            "not found",
+        ));
+    }
+
+    // a client guessing credentials is refused before its guesses are verified
+    let auth = if AUTH_FAILURES.is_exhausted(ip) {
+        None
+    } else {
+        Some(authorize(req.headers()).await)
+    };
+    let refused = match auth {
+        Some(Auth::Granted) => None,
+        Some(Auth::Denied) => {
+            AUTH_FAILURES.check(ip);
+            Some((StatusCode::UNAUTHORIZED, "unauthorized"))
+        }
+        Some(Auth::Busy) => Some((StatusCode::SERVICE_UNAVAILABLE, "verifications busy")),
+        None => Some((StatusCode::TOO_MANY_REQUESTS, "too many failed attempts")),
+    };
+    if let Some((status, detail)) = refused {
+        warn!(
+            target: AUDIT_TARGET,
+            ip = %ip,
+            method = %method,
+            path = %path,
+            status = status.as_u16(),
+            "{}",
+            detail
+        );
+        let mut response_builder = response_builder
+            .status(status)
+            .header("Cache-Control", "no-store");
+        response_builder = if status == StatusCode::UNAUTHORIZED {
+            response_builder.header("WWW-Authenticate", "Bearer realm=\"admin\"")
+        } else {
+            response_builder.header(RETRY_AFTER, "1")
+        };
+        return Ok(response_builder.body(Full::new(Bytes::new())).unwrap());
+    }
+
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').skip(2).collect();
+
+    let response = match (&method, segments.as_slice()) {
+        (&Method::GET, ["users"]) => {
+            let users = ws::list_users();
+            audit!(
+                ip,
+                method,
+                path,
+                StatusCode::OK,
+                format!("{} users", users.len())
+            );
+            json_response(response_builder, StatusCode::OK, &users)
+        }
+
+        (&Method::GET, ["messages"]) => {
+            #[derive(Serialize)]
+            struct Entry {
+                id: u64,
+                at: u64,
+                content: String,
+            }
+            let messages: Vec<Entry> = db::list_messages()
+                .await
+                .into_iter()
+                .map(|msg| Entry {
+                    id: msg.id,
+                    at: msg.at,
+                    content: msg.text,
+                })
+                .collect();
+            audit!(
+                ip,
+                method,
+                path,
+                StatusCode::OK,
+                format!("{} messages", messages.len())
+            );
+            json_response(response_builder, StatusCode::OK, &messages)
+        }
+
+        (&Method::DELETE, ["messages", id]) => {
+            let deleted = match id.parse::<u64>() {
+                Ok(id) => db::delete_message(id).await,
+                Err(_) => None,
+            };
+            match deleted {
+                Some(db::Message {
+                    id, text: content, ..
+                }) => {
+                    audit!(
+                        ip,
+                        method,
+                        path,
+                        StatusCode::OK,
+                        format!("deleted: {content}")
+                    );
+                    let body = serde_json::json!({ "id": id, "content": content });
+                    json_response(response_builder, StatusCode::OK, &body)
+                }
+                None => {
+                    audit!(ip, method, path, StatusCode::NOT_FOUND, "no such message");
+                    error_response(response_builder, StatusCode::NOT_FOUND, "no such message")
+                }
+            }
+        }
+
+        (&Method::POST, ["announce"]) => {
+            let body = Limited::new(req.into_body(), constants::ADMIN_MAX_BODY_SIZE)
+                .collect()
+                .await
+                .map(|collected| collected.to_bytes());
+
+            match body.as_deref().map(std::str::from_utf8) {
+                Ok(Ok(content)) if !content.trim().is_empty() => {
+                    let content = content.trim();
+                    // some users may not receive it if their channel is full or closed
+                    let status = match ws::announce(content) {
+                        Ok(()) => StatusCode::OK,
+                        Err(()) => StatusCode::ACCEPTED,
+                    };
+                    audit!(ip, method, path, status, format!("announcement: {content}"));
+                    let body = serde_json::json!({ "recipients": ws::get_viewer_count() });
+                    json_response(response_builder, status, &body)
+                }
+                Ok(_) => {
+                    audit!(
+                        ip,
+                        method,
+                        path,
+                        StatusCode::BAD_REQUEST,
+                        "empty or invalid body"
+                    );
+                    error_response(
+                        response_builder,
+                        StatusCode::BAD_REQUEST,
+                        "body must be non-empty UTF-8 text",
+                    )
+                }
+                Err(_) => {
+                    audit!(
+                        ip,
+                        method,
+                        path,
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "body too large"
+                    );
+                    error_response(
+                        response_builder,
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "body too large",
+                    )
+                }
+            }
+        }
+
+        (&Method::POST, ["db", action @ ("flush" | "compact")]) => {
+            match db::flush(*action == "compact").await {
+                Ok(written) => {
+                    audit!(
+                        ip,
+                        method,
+                        path,
+                        StatusCode::OK,
+                        format!("{written} messages written")
+                    );
+                    let body = serde_json::json!({ "written": written });
+                    json_response(response_builder, StatusCode::OK, &body)
+                }
+                Err(e) => {
+                    let status = StatusCode::INTERNAL_SERVER_ERROR;
+                    audit!(ip, method, path, status, format!("flush failed: {e}"));
+                    error_response(response_builder, status, "flush failed")
+                }
+            }
+        }
+
+        (&Method::GET, ["config"]) => {
+            audit!(ip, method, path, StatusCode::OK, "config shown");
+            json_response(response_builder, StatusCode::OK, &config::get().redacted())
+        }
+
+        (_, segments) => match allowed_methods(segments) {
+            Some(allow) if method == Method::OPTIONS => response_builder
+                .status(StatusCode::NO_CONTENT)
+                .header(ALLOW, allow)
+                .body(Full::new(Bytes::new()))
+                .unwrap(),
+            Some(allow) => {
+                let status = StatusCode::METHOD_NOT_ALLOWED;
+                audit!(ip, method, path, status, "method not allowed");
+                error_response(
+                    response_builder.header(ALLOW, allow),
+                    status,
+                    "method not allowed",
+                )
+            }
+            None => {
+                audit!(
+                    ip,
+                    method,
+                    path,
+                    StatusCode::NOT_FOUND,
+                    "unknown admin route"
+                );
+                error_response(response_builder, StatusCode::NOT_FOUND, "not found")
+            }
+        },
+    };
+
+    Ok(response)
+}
@@ -0,0 +1,540 @@
+/*  Static files, content pages and the templates
+    - By default the manifest embedded by build.rs is served, files under their hashed urls
+      so browsers can cache them forever, and the pages it compiled
+    - In dev mode (site.dev) assets/ and content/ are served from disk, files under their
+      logical names, they are polled for changes and the pages recompiled, an edit shows
+      up on reload
+    - Both render the sailfish templates compiled from target/user_dir, their urls and
+      digests are looked up in the store when rendered. An edit of templates/ needs a
+      rebuild
+*/
+use crate::compress::{self, Encoding};
+use crate::config;
+use crate::constants;
+use crate::content::{self, Page, PageEntry};
+use crate::db::Message;
+use crate::manifest::{self, AssetRef, ManifestEntry};
+
+use bytes::Bytes;
+use hyper::http::HeaderValue;
+use once_cell::sync::{Lazy, OnceCell};
+use sailfish::{RenderError, TemplateSimple};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{self, Duration};
+use tracing::{debug, error, info};
+
+const CACHE_DEV: &str = "no-cache";
+
+include!(concat!(env!("OUT_DIR"), "/manifest.rs"));
+include!(concat!(env!("OUT_DIR"), "/pages.rs"));
+
+#[derive(TemplateSimple)]
+#[template(path = "../target/user_dir/index.html")] // pre-templated by build.rs
+#[template(rm_whitespace = true)]
+struct Template<'a> {
+    pub nbusers: &'a usize,
+    pub nonce: &'a str,
+    pub messages: &'a [Message],
+}
+
+#[derive(TemplateSimple)]
+#[template(path = "../target/user_dir/error.html")] // pre-templated by build.rs
+#[template(rm_whitespace = true)]
+struct ErrorTemplate<'a> {
+    pub status: u16,
+    pub reason: &'a str,
+    pub request_id: &'a str,
+}
+
+#[derive(TemplateSimple)]
+#[template(path = "../target/user_dir/layout.html")] // pre-templated by build.rs
+#[template(rm_whitespace = true)]
+struct LayoutTemplate<'a> {
+    pub nonce: &'a str,
+    pub title: &'a str,
+    pub description: &'a str,
+    pub meta: &'a str,
+    pub content: &'a str,
+}
+
+// what the layout shows, a content page or a page generated by the server
+pub struct Layout<'a> {
+    pub title: &'a str,
+    pub description: &'a str,
+    pub meta: &'a str, // HTML under the heading, e.g. the date and tags of a post
+    pub content: &'a str, // HTML
+}
+
+impl<'a> From<&'a Page> for Layout<'a> {
+    fn from(page: &'a Page) -> Self {
+        Self {
+            title: &page.title,
+            description: &page.description,
+            meta: "",
+            content: &page.html,
+        }
+    }
+}
+
+// a file served as is, or one of its precompressed variants
+#[derive(Clone)]
+pub struct Asset {
+    pub body: Bytes,
+    pub content_type: &'static str,
+    pub cache_control: &'static str,
+    pub br: Option<Bytes>,
+    pub gzip: Option<Bytes>,
+    pub hash: Cow<'static, str>, // of the uncompressed body, hex
+    pub modified: SystemTime,
+}
+
+impl Asset {
+    // responses differ by Accept-Encoding, caches have to know (Vary)
+    pub fn has_variants(&self) -> bool {
+        config::get().compression.enabled && (self.br.is_some() || self.gzip.is_some())
+    }
+
+    // the body to send for the Accept-Encoding header of a request
+    pub fn negotiate(&self, accept_encoding: Option<&HeaderValue>) -> (Encoding, Bytes) {
+        if !self.has_variants() {
+            return (Encoding::Identity, self.body.clone());
+        }
+
+        let mut available = Vec::with_capacity(2);
+        if self.br.is_some() {
+            available.push(Encoding::Brotli);
+        }
+        if self.gzip.is_some() {
+            available.push(Encoding::Gzip);
+        }
+
+        match compress::negotiate(accept_encoding, &available) {
+            Encoding::Brotli => (Encoding::Brotli, self.br.clone().unwrap()),
+            Encoding::Gzip => (Encoding::Gzip, self.gzip.clone().unwrap()),
+            Encoding::Identity => (Encoding::Identity, self.body.clone()),
+        }
+    }
+
+    // strong ETag of the body sent with `encoding`, each encoding is its own representation
+    pub fn etag(&self, encoding: Encoding) -> String {
+        let hash = &self.hash[..self.hash.len().min(16)];
+        match encoding {
+            Encoding::Identity => format!("\"{hash}\""),
+            _ => format!("\"{hash}-{}\"", encoding.as_str()),
+        }
+    }
+}
+
+// where the handler gets static files and the index page from
+pub trait AssetStore: Send + Sync {
+    // file served at `path`, if any
+    fn get(&self, path: &str) -> Option<Asset>;
+
+    // url and digest of the file `name` of assets/, for the templates
+    fn asset_ref(&self, name: &str) -> Option<AssetRef>;
+
+    fn render_index(
+        &self,
+        nbusers: &usize,
+        nonce: &str,
+        messages: &[Message],
+    ) -> Result<String, RenderError> {
+        Template {
+            nbusers,
+            nonce,
+            messages,
+        }
+        .render_once()
+    }
+
+    // page of the error responses that have no body of their own
+    fn render_error(
+        &self,
+        status: u16,
+        reason: &str,
+        request_id: &str,
+    ) -> Result<String, RenderError> {
+        ErrorTemplate {
+            status,
+            reason,
+            request_id,
+        }
+        .render_once()
+    }
+
+    // content page served at `path`, if any
+    fn page(&self, path: &str) -> Option<Arc<Page>>;
+
+    // every content page, by path
+    fn pages(&self) -> Vec<Arc<Page>>;
+
+    // a page in the layout, the nonce is the one of the request as for the index
+    fn render_layout(&self, layout: &Layout, nonce: &str) -> Result<String, RenderError> {
+        LayoutTemplate {
+            nonce,
+            title: layout.title,
+            description: layout.description,
+            meta: layout.meta,
+            content: layout.content,
+        }
+        .render_once()
+    }
+
+    // changes whenever the index template does, part of the ETag of the homepage
+    fn generation(&self) -> u64 {
+        0
+    }
+
+    // when the current files were built or loaded, the date of what is made from them
+    fn loaded_at(&self) -> SystemTime {
+        *BUILD_TIME
+    }
+
+    // digests of the scripts as CSP sources, for the {script_hashes} of the security headers
+    fn script_hashes(&self) -> String;
+}
+
+// {{asset:name}} of the templates, as build.rs wrote it into target/user_dir
+fn asset_url(name: &str) -> Result<String, RenderError> {
+    get()
+        .asset_ref(name)
+        .map(|asset| asset.url)
+        .ok_or_else(|| RenderError::Msg(format!("unknown asset '{name}'")))
+}
+
+// {{integrity:name}} of the templates
+fn asset_integrity(name: &str) -> Result<String, RenderError> {
+    get()
+        .asset_ref(name)
+        .map(|asset| asset.integrity)
+        .ok_or_else(|| RenderError::Msg(format!("unknown asset '{name}'")))
+}
+
+// 'sha384-...' sources of the scripts among `assets` (content type, integrity)
+fn script_hashes<'a>(assets: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    assets
+        .filter(|(content_type, _)| manifest::is_script(content_type))
+        .map(|(_, integrity)| format!("'{integrity}'"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/********* release *********/
+
+// the manifest by url
+static BY_URL: Lazy<HashMap<&'static str, &'static ManifestEntry>> =
+    Lazy::new(|| MANIFEST.iter().map(|entry| (entry.url, entry)).collect());
+
+// the manifest by name in assets/
+static BY_NAME: Lazy<HashMap<&'static str, &'static ManifestEntry>> =
+    Lazy::new(|| MANIFEST.iter().map(|entry| (entry.name, entry)).collect());
+
+// set by build.rs, the embedded files are as old as the build
+static BUILD_TIME: Lazy<SystemTime> = Lazy::new(|| {
+    let secs = env!("BUILD_TIMESTAMP").parse::<u64>().unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+});
+
+static SCRIPT_HASHES: Lazy<String> = Lazy::new(|| {
+    script_hashes(
+        MANIFEST
+            .iter()
+            .map(|entry| (entry.content_type, entry.integrity)),
+    )
+});
+
+// the pages compiled by build.rs, by path
+static PAGES_BY_PATH: Lazy<BTreeMap<&'static str, Arc<Page>>> = Lazy::new(|| {
+    PAGES
+        .iter()
+        .map(|entry| (entry.path, Arc::new(Page::from(entry))))
+        .collect()
+});
+
+struct EmbeddedStore;
+
+impl AssetStore for EmbeddedStore {
+    fn get(&self, path: &str) -> Option<Asset> {
+        BY_URL.get(path).map(|entry| Asset {
+            body: Bytes::from_static(entry.bytes),
+            content_type: entry.content_type,
+            cache_control: entry.cache_control,
+            br: entry.br.map(Bytes::from_static),
+            gzip: entry.gzip.map(Bytes::from_static),
+            hash: Cow::Borrowed(entry.hash),
+            modified: *BUILD_TIME,
+        })
+    }
+
+    fn asset_ref(&self, name: &str) -> Option<AssetRef> {
+        BY_NAME.get(name).map(|entry| AssetRef {
+            url: entry.url.to_string(),
+            integrity: entry.integrity.to_string(),
+        })
+    }
+
+    fn page(&self, path: &str) -> Option<Arc<Page>> {
+        PAGES_BY_PATH.get(path).cloned()
+    }
+
+    fn pages(&self) -> Vec<Arc<Page>> {
+        PAGES_BY_PATH.values().cloned().collect()
+    }
+
+    fn script_hashes(&self) -> String {
+        SCRIPT_HASHES.clone()
+    }
+}
+
+/********* dev mode *********/
+
+// not the sha256 of the build, but enough to tell two versions of a file apart
+fn dev_hash(bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+struct Loaded {
+    files: HashMap<String, Asset>,      // by url
+    pages: BTreeMap<String, Arc<Page>>, // by path
+    refs: HashMap<String, AssetRef>,    // by name, for the templates
+    script_hashes: String,
+    at: SystemTime,
+}
+
+fn load(assets_dir: &Path, templates_dir: &Path, content_dir: &Path) -> Result<Loaded, String> {
+    let error = |path: &Path, e: &dyn std::fmt::Display| format!("{}: {e}", path.display());
+
+    // plain files first, then templated ones which may refer to them, as build.rs does
+    let names = manifest::walk(assets_dir).map_err(|e| error(assets_dir, &e))?;
+    let mut contents = Vec::with_capacity(names.len());
+    for (name, path) in names {
+        let bytes = fs::read(&path).map_err(|e| error(&path, &e))?;
+        contents.push((name, path, bytes));
+    }
+    contents.sort_by_key(|(_, _, bytes)| manifest::is_templated(bytes));
+
+    // in dev mode assets are served under their logical name
+    let mut refs: HashMap<String, AssetRef> = HashMap::new();
+    let mut files = HashMap::new();
+    for (name, path, bytes) in contents {
+        let modified = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .map_err(|e| error(&path, &e))?;
+        let body = if manifest::is_templated(&bytes) {
+            manifest::substitute(&String::from_utf8_lossy(&bytes), |name| {
+                refs.get(name).cloned()
+            })
+            .map_err(|e| error(&path, &e))?
+            .into_bytes()
+        } else {
+            bytes
+        };
+        refs.insert(
+            name.clone(),
+            AssetRef {
+                url: format!("/{name}"),
+                integrity: manifest::integrity(&body),
+            },
+        );
+        files.insert(
+            format!("/{name}"),
+            Asset {
+                hash: Cow::Owned(dev_hash(&body)),
+                body: Bytes::from(body),
+                content_type: manifest::content_type(&name),
+                cache_control: CACHE_DEV,
+                br: None,
+                gzip: None,
+                modified,
+            },
+        );
+    }
+    let script_hashes = script_hashes(
+        refs.iter()
+            .map(|(name, asset)| (manifest::content_type(name), asset.integrity.as_str())),
+    );
+
+    // the compiled layout renders the pages, its source with the urls of this load is
+    // part of their hash as in build.rs
+    let ref_of = |name: &str| refs.get(name).cloned();
+    let path = templates_dir.join("layout.html");
+    let layout_source = fs::read_to_string(&path)
+        .map_err(|e| error(&path, &e))
+        .and_then(|source| manifest::substitute(&source, ref_of).map_err(|e| error(&path, &e)))?;
+
+    // a site without content/ has no pages
+    let names = if content_dir.exists() {
+        manifest::walk(content_dir).map_err(|e| error(content_dir, &e))?
+    } else {
+        Vec::new()
+    };
+    let mut pages = BTreeMap::new();
+    for (name, path) in names.iter().filter(|(name, _)| content::is_page(name)) {
+        let source = fs::read_to_string(path).map_err(|e| error(path, &e))?;
+        let source = manifest::substitute(&source, ref_of).map_err(|e| error(path, &e))?;
+        let modified = fs::metadata(path)
+            .and_then(|m| m.modified())
+            .map_err(|e| error(path, &e))?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let page = content::compile(name, &source, &layout_source, modified)
+            .map_err(|e| error(path, &e))?;
+        pages.insert(page.path.clone(), Arc::new(page));
+    }
+
+    Ok(Loaded {
+        files,
+        pages,
+        refs,
+        script_hashes,
+        at: SystemTime::now(),
+    })
+}
+
+// latest modification time of the files in `dirs`, and how many there are
+fn modified(dirs: &[&Path]) -> (Option<SystemTime>, usize) {
+    let files: Vec<PathBuf> = dirs
+        .iter()
+        .filter_map(|dir| manifest::walk(dir).ok())
+        .flatten()
+        .map(|(_, path)| path)
+        .collect();
+    let latest = files
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok()?.modified().ok())
+        .max();
+    (latest, files.len())
+}
+
+struct DevStore {
+    loaded: Arc<RwLock<Arc<Loaded>>>, // shared with the task watching the files
+    reloads: Arc<AtomicU64>,
+}
+
+impl DevStore {
+    fn current(&self) -> Arc<Loaded> {
+        self.loaded.read().unwrap().clone()
+    }
+}
+
+impl AssetStore for DevStore {
+    fn get(&self, path: &str) -> Option<Asset> {
+        self.current().files.get(path).cloned()
+    }
+
+    fn asset_ref(&self, name: &str) -> Option<AssetRef> {
+        self.current().refs.get(name).cloned()
+    }
+
+    fn page(&self, path: &str) -> Option<Arc<Page>> {
+        self.current().pages.get(path).cloned()
+    }
+
+    fn pages(&self) -> Vec<Arc<Page>> {
+        self.current().pages.values().cloned().collect()
+    }
+
+    fn generation(&self) -> u64 {
+        self.reloads.load(Ordering::Relaxed)
+    }
+
+    fn loaded_at(&self) -> SystemTime {
+        self.current().at
+    }
+
+    fn script_hashes(&self) -> String {
+        self.current().script_hashes.clone()
+    }
+}
+
+// starts dev mode, the files are reloaded whenever one of them changes
+fn initialize_dev() -> Result<DevStore, String> {
+    let assets_dir = Path::new(manifest::ASSETS_DIR);
+    let templates_dir = Path::new(constants::DEV_TEMPLATES_DIR);
+    let content_dir = Path::new(content::CONTENT_DIR);
+    let store = DevStore {
+        loaded: Arc::new(RwLock::new(Arc::new(load(
+            assets_dir,
+            templates_dir,
+            content_dir,
+        )?))),
+        reloads: Arc::new(AtomicU64::new(0)),
+    };
+
+    let watched = store.loaded.clone();
+    let reloads = store.reloads.clone();
+    tokio::spawn(async move {
+        let mut last = modified(&[assets_dir, content_dir]);
+        let mut interval = time::interval(Duration::from_millis(constants::DEV_WATCH_INTERVAL));
+        loop {
+            interval.tick().await;
+
+            // a removed file changes the count, not the latest time
+            let current = modified(&[assets_dir, content_dir]);
+            if current == last {
+                continue;
+            }
+            last = current;
+
+            // a broken file keeps the previous version until it is fixed
+            match load(assets_dir, templates_dir, content_dir) {
+                Ok(loaded) => {
+                    *watched.write().unwrap() = Arc::new(loaded);
+                    reloads.fetch_add(1, Ordering::Relaxed);
+                    info!("Reloaded assets and content");
+                }
+                Err(e) => error!(error = %e, "Failed to reload assets and content"),
+            }
+        }
+    });
+
+    Ok(store)
+}
+
+/********* store *********/
+
+static STORE: OnceCell<Box<dyn AssetStore>> = OnceCell::new();
+
+pub fn initialize() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let store: Box<dyn AssetStore> = if config::get().site.dev {
+        let store = initialize_dev()?;
+        info!(
+            assets = manifest::ASSETS_DIR,
+            content = content::CONTENT_DIR,
+            "Dev mode, serving assets and content from disk"
+        );
+        Box::new(store)
+    } else {
+        for entry in MANIFEST {
+            debug!(
+                name = entry.name,
+                url = entry.url,
+                hash = entry.hash,
+                integrity = entry.integrity,
+                "Embedded asset"
+            );
+        }
+        for entry in PAGES {
+            debug!(path = entry.path, lastmod = entry.lastmod, "Embedded page");
+        }
+        Box::new(EmbeddedStore)
+    };
+
+    STORE.set(store).map_err(|_| "assets already initialized")?;
+    Ok(())
+}
+
+#[inline(always)]
+pub fn get() -> &'static dyn AssetStore {
+    STORE.get_or_init(|| Box::new(EmbeddedStore)).as_ref()
+}
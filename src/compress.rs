@@ -0,0 +1,244 @@
+/*  Response compression
+    - Static assets come with Brotli and gzip variants made by build.rs, picked here from
+      the Accept-Encoding of the request
+    - Dynamic HTML and JSON bodies over compression.min_size are compressed on the fly,
+      with faster settings than the build
+*/
+use crate::accept;
+use crate::config;
+use crate::constants;
+
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use hyper::http::HeaderValue;
+use hyper::{Response, StatusCode};
+use std::io::Write;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    // in order of preference when the client accepts several with the same q-value
+    const PREFERENCE: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Identity];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Identity => "identity",
+        }
+    }
+}
+
+// q-value given to `encoding` by an Accept-Encoding header (RFC 9110, 12.5.3):
+// explicit entries win over "*", and identity is acceptable unless excluded
+fn q_value(accept: &str, encoding: Encoding) -> f32 {
+    let mut explicit = None;
+    let mut wildcard = None;
+
+    for (coding, q) in accept::items(accept) {
+        if coding.eq_ignore_ascii_case(encoding.as_str())
+            || (encoding == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+        {
+            explicit = Some(q);
+        } else if coding == "*" {
+            wildcard = Some(q);
+        }
+    }
+
+    match (explicit, wildcard, encoding) {
+        (Some(q), _, _) | (None, Some(q), _) => q,
+        // acceptable, but less wanted than anything the client asked for
+        (None, None, Encoding::Identity) => 0.001,
+        (None, None, _) => 0.0,
+    }
+}
+
+// the best encoding among `available` for the Accept-Encoding header of a request,
+// identity is always available
+pub fn negotiate(accept_encoding: Option<&HeaderValue>, available: &[Encoding]) -> Encoding {
+    let Some(accept) = accept_encoding.and_then(|v| v.to_str().ok()) else {
+        return Encoding::Identity;
+    };
+
+    let mut best = (Encoding::Identity, 0.0);
+    for encoding in Encoding::PREFERENCE {
+        if encoding != Encoding::Identity && !available.contains(&encoding) {
+            continue;
+        }
+        let q = q_value(accept, encoding);
+        if q > best.1 {
+            best = (encoding, q);
+        }
+    }
+    // nothing acceptable, the body is sent as is rather than answering 406
+    best.0
+}
+
+pub fn compress(body: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut out = Vec::new();
+            let params = brotli::enc::BrotliEncoderParams {
+                quality: constants::COMPRESS_BROTLI_QUALITY,
+                ..Default::default()
+            };
+            brotli::BrotliCompress(&mut &body[..], &mut out, &params)?;
+            Ok(out)
+        }
+        Encoding::Gzip => {
+            let mut encoder =
+                GzEncoder::new(Vec::new(), Compression::new(constants::COMPRESS_GZIP_LEVEL));
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Identity => Ok(body.to_vec()),
+    }
+}
+
+// html and json responses are compressed on the fly
+fn is_dynamic(content_type: &str) -> bool {
+    content_type.starts_with("text/html") || content_type.starts_with("application/json")
+}
+
+// compresses a dynamic response when worth it, for the Accept-Encoding header of the
+// request, responses that already have a Content-Encoding are left alone
+pub async fn dynamic(
+    accept_encoding: Option<&HeaderValue>,
+    res: Response<Full<Bytes>>,
+) -> Response<Full<Bytes>> {
+    let cfg = &config::get().compression;
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+
+    if !cfg.enabled
+        || res.status() != StatusCode::OK
+        || res.headers().contains_key(CONTENT_ENCODING)
+        || !is_dynamic(content_type)
+    {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    // the body may or may not be compressed depending on the request
+    let varies = parts
+        .headers
+        .get_all(VARY)
+        .iter()
+        .any(|v| v.as_bytes().eq_ignore_ascii_case(b"Accept-Encoding"));
+    if !varies {
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+
+    let body = body
+        .collect()
+        .await
+        .map(|c| c.to_bytes())
+        .unwrap_or_default();
+    if body.len() < cfg.min_size {
+        return Response::from_parts(parts, Full::new(body));
+    }
+
+    let encoding = negotiate(accept_encoding, &[Encoding::Brotli, Encoding::Gzip]);
+    if encoding == Encoding::Identity {
+        return Response::from_parts(parts, Full::new(body));
+    }
+
+    match compress(&body, encoding) {
+        Ok(compressed) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.insert(
+                CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+            Response::from_parts(parts, Full::new(Bytes::from(compressed)))
+        }
+        Err(e) => {
+            warn!(error = %e, encoding = encoding.as_str(), "Failed to compress response");
+            Response::from_parts(parts, Full::new(body))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOTH: &[Encoding] = &[Encoding::Brotli, Encoding::Gzip];
+
+    fn pick(accept: &'static str, available: &[Encoding]) -> Encoding {
+        negotiate(Some(&HeaderValue::from_static(accept)), available)
+    }
+
+    #[test]
+    fn without_header_is_identity() {
+        assert_eq!(negotiate(None, BOTH), Encoding::Identity);
+        assert_eq!(pick("", BOTH), Encoding::Identity);
+    }
+
+    #[test]
+    fn ties_prefer_brotli() {
+        assert_eq!(pick("gzip, br", BOTH), Encoding::Brotli);
+        assert_eq!(pick("gzip;q=0.8, br;q=0.8", BOTH), Encoding::Brotli);
+        assert_eq!(pick("br;q=0.5, gzip", BOTH), Encoding::Gzip);
+        assert_eq!(pick("gzip, br", &[Encoding::Gzip]), Encoding::Gzip);
+        // asked for, but there is no such variant
+        assert_eq!(pick("br", &[Encoding::Gzip]), Encoding::Identity);
+    }
+
+    #[test]
+    fn q_zero_excludes() {
+        assert_eq!(pick("br;q=0, gzip", BOTH), Encoding::Gzip);
+        assert_eq!(pick("br;q=0, gzip;q=0", BOTH), Encoding::Identity);
+        assert_eq!(pick("gzip; q=0.000", &[Encoding::Gzip]), Encoding::Identity);
+        // out of range values are clamped
+        assert_eq!(pick("br;q=2, gzip;q=-1", BOTH), Encoding::Brotli);
+    }
+
+    #[test]
+    fn wildcard() {
+        assert_eq!(pick("*", BOTH), Encoding::Brotli);
+        assert_eq!(pick("*, br;q=0", BOTH), Encoding::Gzip);
+        assert_eq!(pick("gzip;q=0.5, *;q=0.1", BOTH), Encoding::Gzip);
+        // an explicit entry wins over the wildcard, whatever their order
+        assert_eq!(pick("br;q=0.2, *;q=0.9", BOTH), Encoding::Gzip);
+        assert_eq!(pick("*;q=0", BOTH), Encoding::Identity);
+    }
+
+    #[test]
+    fn x_gzip_and_case() {
+        assert_eq!(pick("x-gzip", BOTH), Encoding::Gzip);
+        assert_eq!(pick("GZIP", BOTH), Encoding::Gzip);
+        assert_eq!(pick("Br", BOTH), Encoding::Brotli);
+    }
+
+    #[test]
+    fn identity_refused() {
+        assert_eq!(q_value("identity;q=0", Encoding::Identity), 0.0);
+        assert_eq!(q_value("*;q=0", Encoding::Identity), 0.0);
+        assert_eq!(q_value("gzip", Encoding::Identity), 0.001);
+        assert_eq!(
+            pick("identity;q=0, gzip;q=0.5", &[Encoding::Gzip]),
+            Encoding::Gzip
+        );
+        // nothing acceptable is left, the body is sent as is rather than a 406
+        assert_eq!(pick("identity;q=0", &[]), Encoding::Identity);
+        assert_eq!(
+            pick("identity;q=0, br", &[Encoding::Gzip]),
+            Encoding::Identity
+        );
+    }
+}
@@ -0,0 +1,144 @@
+/*  Conditional requests (RFC 9110, 13)
+    - Static assets have a strong ETag from the hash build.rs computed, one per encoding,
+      and a Last-Modified
+    - The homepage has a weak ETag that changes with the messages, the user count and the
+      nonce are left out of it
+    - If-None-Match takes precedence, If-Modified-Since is only checked without it
+*/
+use hyper::HeaderMap;
+use hyper::header::{IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// the opaque part of an entity tag, weak comparison ignores the W/ prefix
+fn opaque(tag: &str) -> &str {
+    tag.trim().strip_prefix("W/").unwrap_or(tag.trim())
+}
+
+// true if the client already has the representation with `etag`, last modified at
+// `last_modified`, and can be answered 304 Not Modified
+pub fn not_modified(headers: &HeaderMap, etag: &str, last_modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        return if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag));
+    }
+
+    let (Some(last_modified), Some(since)) = (
+        last_modified,
+        headers
+            .get(IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok()),
+    ) else {
+        return false;
+    };
+    // HTTP dates have a one second resolution
+    truncate(last_modified) <= since
+}
+
+pub fn http_date(time: SystemTime) -> String {
+    httpdate::fmt_http_date(truncate(time))
+}
+
+fn truncate(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    // 2025-06-13 10:20:30 UTC
+    fn time(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_749_810_030_000 + millis)
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let matches = |tags, etag| not_modified(&headers(&[("if-none-match", tags)]), etag, None);
+        assert!(matches("\"abc\"", "\"abc\""));
+        assert!(matches("W/\"abc\"", "\"abc\""));
+        assert!(matches("\"abc\"", "W/\"abc\""));
+        assert!(matches(" W/\"abc\" ", "W/\"abc\""));
+        assert!(!matches("\"abc\"", "\"abd\""));
+        assert!(!matches("\"abc-br\"", "\"abc\""));
+    }
+
+    #[test]
+    fn if_none_match_lists_and_star() {
+        let matches = |tags, etag| not_modified(&headers(&[("if-none-match", tags)]), etag, None);
+        assert!(matches("\"x\", W/\"y\", \"abc\"", "\"abc\""));
+        assert!(!matches("\"x\", \"y\"", "\"abc\""));
+        assert!(matches("*", "\"abc\""));
+        assert!(matches("\"x\", *", "\"abc\""));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let since = |date| headers(&[("if-modified-since", date)]);
+        let modified = Some(time(500));
+        // the fraction of a second is not part of the date
+        assert!(not_modified(
+            &since("Fri, 13 Jun 2025 10:20:30 GMT"),
+            "\"a\"",
+            modified
+        ));
+        assert!(not_modified(
+            &since("Fri, 13 Jun 2025 10:20:31 GMT"),
+            "\"a\"",
+            modified
+        ));
+        assert!(!not_modified(
+            &since("Fri, 13 Jun 2025 10:20:29 GMT"),
+            "\"a\"",
+            modified
+        ));
+        assert!(!not_modified(&since("yesterday"), "\"a\"", modified));
+        assert!(!not_modified(
+            &since("Fri, 13 Jun 2025 10:20:30 GMT"),
+            "\"a\"",
+            None
+        ));
+        assert!(!not_modified(&HeaderMap::new(), "\"a\"", modified));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let modified = Some(time(0));
+        let both = |tags| {
+            headers(&[
+                ("if-none-match", tags),
+                ("if-modified-since", "Fri, 13 Jun 2025 10:20:30 GMT"),
+            ])
+        };
+        // a date that would match does not make up for a changed ETag
+        assert!(!not_modified(&both("\"old\""), "\"new\"", modified));
+        assert!(not_modified(
+            &both("\"new\""),
+            "\"new\"",
+            Some(time(3_600_000))
+        ));
+    }
+
+    #[test]
+    fn http_date_format() {
+        assert_eq!(http_date(time(999)), "Fri, 13 Jun 2025 10:20:30 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+}
@@ -0,0 +1,442 @@
+use crate::constants;
+use crate::ip::Cidr;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+// runtime configuration, read once from CONFIG_FILE at startup
+// every field has a default so the file (or any section of it) may be omitted
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub admin: AdminConfig,
+    pub compression: CompressionConfig,
+    pub csp_report: CspReportConfig,
+    pub http2: Http2Config,
+    pub limits: LimitsConfig,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+    pub proxy: ProxyConfig,
+    pub rate_limit: RateLimitConfig,
+    pub security: SecurityConfig,
+    pub site: SiteConfig,
+    pub tls: TlsConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub token: Option<String>, // accepted as "Authorization: Bearer <token>"
+    pub username: String,      // accepted as "Authorization: Basic <username:password>"
+    pub password_hash: Option<String>, // argon2 PHC string, e.g. "$argon2id$v=19$..."
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            username: "admin".to_string(),
+            password_hash: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    File,
+    Stdout,
+    Both,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: String, // EnvFilter spec, e.g. "info,webrs::ws=debug", RUST_LOG takes precedence
+    pub format: LogFormat,
+    pub output: LogOutput,
+    pub rotation: RotationConfig,
+}
+
+// rotation of LOG_FILE, a value of 0 disables the corresponding limit
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RotationConfig {
+    pub max_size: u64,     // rotate once the file would grow past this size (in bytes)
+    pub daily: bool,       // rotate when the day (UTC) changes
+    pub compress: bool,    // gzip rotated files
+    pub max_files: usize,  // number of rotated files to keep
+    pub max_age_days: u64, // delete rotated files older than this
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            max_size: constants::LOG_MAX_SIZE,
+            daily: true,
+            compress: true,
+            max_files: constants::LOG_MAX_FILES,
+            max_age_days: 0,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Human,
+            output: LogOutput::File,
+            rotation: RotationConfig::default(),
+        }
+    }
+}
+
+// where the client address is read from when the peer is a trusted proxy
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyMode {
+    Cloudflare,    // CF-Connecting-IP
+    XForwardedFor, // right-most untrusted entry of X-Forwarded-For
+    Forwarded,     // right-most untrusted "for" of RFC 7239 Forwarded
+    ProxyProtocol, // PROXY protocol v1/v2 header sent by a trusted peer before HTTP
+    None,          // always the TCP peer address
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub mode: ProxyMode,
+    pub trusted: Vec<Cidr>, // networks of the proxies allowed to set forwarding headers
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        // loopback and private networks, where the reverse proxy and docker bridge live
+        let trusted = [
+            "127.0.0.0/8",
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "::1/128",
+            "fc00::/7",
+        ];
+        Self {
+            mode: ProxyMode::Cloudflare,
+            trusted: trusted.iter().filter_map(|c| c.parse().ok()).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub enabled: bool, // precompressed assets and on the fly compression of html and json
+    pub min_size: usize, // in bytes, smaller dynamic responses are sent as is
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size: 1024,
+        }
+    }
+}
+
+// POST /csp-report, named in the report-uri and report-to of every CSP when enabled
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CspReportConfig {
+    pub enabled: bool,
+    pub per_second: u32, // reports a second per client IP, on average
+    pub burst: u32,      // reports allowed at once per client IP
+}
+
+impl Default for CspReportConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            per_second: 1,
+            burst: 10,
+        }
+    }
+}
+
+// HTTP/2 is negotiated with ALPN over TLS, or detected from the preface on plain connections (h2c)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http2Config {
+    pub enabled: bool,
+    pub max_concurrent_streams: u32,
+    pub max_header_list_size: u32, // in bytes
+    pub keep_alive_interval: u64,  // seconds between PING frames on idle connections, 0 disables
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_concurrent_streams: 100,
+            max_header_list_size: 16 * 1024,
+            keep_alive_interval: 0,
+        }
+    }
+}
+
+// what happens to a connection accepted while max_connections are open
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WhenFull {
+    Reject, // answer 503 Service Unavailable (plain HTTP only, TLS connections are closed)
+    Close,  // close the connection without a word
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: usize, // open connections over both listeners
+    pub when_full: WhenFull,
+    pub header_read_timeout: u64, // seconds to receive the request headers (HTTP/1), 0 disables
+    pub idle_timeout: u64, // seconds a connection may stay open without a request, 0 disables
+    pub max_header_size: usize, // in bytes (HTTP/1), at least 8192
+    pub max_body_size: usize, // in bytes, larger requests are answered 413
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            when_full: WhenFull::Reject,
+            header_read_timeout: 10,
+            idle_timeout: 60,
+            max_header_size: 16 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+// headers sent with every response of the main listener, {nonce} in a value is replaced by
+// the nonce of the request (the one the templates get) and {script_hashes} by the sha384
+// digests of the scripts, an empty value sends no header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    pub csp: String,                                        // Content-Security-Policy
+    pub report_to: String, // Report-To, JSON of the reporting endpoint groups
+    pub headers: BTreeMap<String, String>, // the other ones, by name
+    pub routes: BTreeMap<String, BTreeMap<String, String>>, // by path prefix, the longest wins, headers set or removed there
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        let headers = [
+            ("X-Permitted-Cross-Domain-Policies", "none"),
+            ("X-Content-Type-Options", "nosniff"),
+            ("X-Frame-Options", "DENY"),
+            ("Referrer-Policy", "no-referrer"),
+            (
+                "Permissions-Policy",
+                "geolocation=(), microphone=(), camera=()",
+            ),
+            ("Cross-Origin-Resource-Policy", "same-origin"),
+            ("Cross-Origin-Opener-Policy", "same-origin"),
+            ("Cross-Origin-Embedder-Policy", "require-corp"),
+        ];
+        Self {
+            csp: "default-src 'none'; script-src 'nonce-{nonce}' {script_hashes}; style-src 'self'; img-src 'self'; connect-src 'self'; base-uri 'none'; form-action 'self'; frame-ancestors 'none'".to_string(),
+            report_to: String::new(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            routes: BTreeMap::new(),
+        }
+    }
+}
+
+// the public site, for the absolute urls of the sitemap and the feeds
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiteConfig {
+    pub url: String,   // scheme and host the site is reached at, without a trailing slash
+    pub title: String, // of the post feeds
+    pub author: String, // of the posts, in the feeds
+    pub dev: bool,     // serve assets/ and content/ from disk, reloaded on changes
+}
+
+impl Default for SiteConfig {
+    fn default() -> Self {
+        Self {
+            url: "https://www.bigmike.ch".to_string(),
+            title: "Big Mike's Website".to_string(),
+            author: "Big Mike".to_string(),
+            dev: cfg!(debug_assertions),
+        }
+    }
+}
+
+// per client IP, over every route of the main listener
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub per_second: u32, // requests a second, on average
+    pub burst: u32,      // requests allowed at once
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            per_second: 20,
+            burst: 40,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    pub cert: PathBuf, // PEM certificate chain, reloaded when it changes
+    pub key: PathBuf,  // PEM private key, reloaded when it changes
+    pub hsts: String,  // Strict-Transport-Security sent over TLS, empty to disable
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: SocketAddr::from(constants::TLS_HOST),
+            cert: PathBuf::from("data/tls/cert.pem"),
+            key: PathBuf::from("data/tls/key.pem"),
+            hsts: "max-age=63072000; includeSubDomains".to_string(),
+        }
+    }
+}
+
+// off by default, the counters are not for the public, prefer a separate listener
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub listen: Option<SocketAddr>, // serve /metrics on this address only, instead of the main listener
+}
+
+impl AdminConfig {
+    // the admin API is only served when at least one credential is configured
+    pub fn enabled(&self) -> bool {
+        self.token.is_some() || self.password_hash.is_some()
+    }
+
+    // a credential that is set must be usable, rather than enable the API without a secret
+    fn validate(&self) -> Result<(), String> {
+        if let Some(token) = &self.token
+            && token.trim().len() < constants::ADMIN_TOKEN_MIN_LENGTH
+        {
+            return Err(format!(
+                "admin.token must be at least {} characters long",
+                constants::ADMIN_TOKEN_MIN_LENGTH
+            ));
+        }
+        if let Some(hash) = &self.password_hash {
+            argon2::PasswordHash::new(hash)
+                .map_err(|e| format!("admin.password_hash is not an argon2 PHC string: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    // copy of the config that is safe to show, secrets are replaced
+    pub fn redacted(&self) -> Config {
+        let mut cfg = self.clone();
+        let hide = |s: &mut Option<String>| {
+            if s.is_some() {
+                *s = Some("<redacted>".to_string());
+            }
+        };
+        hide(&mut cfg.admin.token);
+        hide(&mut cfg.admin.password_hash);
+        cfg
+    }
+}
+
+static GLOBAL_CONFIG: OnceCell<Config> = OnceCell::new();
+
+pub fn initialize() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config: Config = match fs::read_to_string(constants::CONFIG_FILE) {
+        Ok(contents) => toml::from_str(&contents)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+        Err(e) => return Err(e.into()),
+    };
+    config.admin.validate()?;
+
+    GLOBAL_CONFIG
+        .set(config)
+        .map_err(|_| "configuration already initialized")?;
+    Ok(())
+}
+
+#[inline(always)]
+pub fn get() -> &'static Config {
+    GLOBAL_CONFIG.get_or_init(Config::default)
+}
+
+// the configuration of every test, set by the first one that needs it: short limits so
+// the slow sockets of the tests give up quickly, HTTP/2 keep-alive on, the rest as shipped
+#[cfg(test)]
+pub fn set_for_tests() {
+    let _ = GLOBAL_CONFIG.set(Config {
+        limits: LimitsConfig {
+            header_read_timeout: 1,
+            idle_timeout: 2,
+            max_body_size: 1024,
+            ..Default::default()
+        },
+        http2: Http2Config {
+            keep_alive_interval: 60,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin(token: Option<&str>, password_hash: Option<&str>) -> AdminConfig {
+        AdminConfig {
+            token: token.map(str::to_string),
+            password_hash: password_hash.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn admin_credentials_are_validated() {
+        assert!(admin(None, None).validate().is_ok());
+        assert!(admin(Some("0123456789abcdef"), None).validate().is_ok());
+        assert!(admin(Some(""), None).validate().is_err());
+        assert!(admin(Some("   short token  "), None).validate().is_err());
+
+        let hash =
+            "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHRzYWx0$RdescudvJCsgt3ub+b+dWRWJTmaaJObG";
+        assert!(admin(None, Some(hash)).validate().is_ok());
+        assert!(admin(None, Some("")).validate().is_err());
+        assert!(admin(None, Some("secret")).validate().is_err());
+    }
+}
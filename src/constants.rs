@@ -1,22 +1,36 @@
-use std::env;
-
 /********* main.rs *********/
 pub const MAIN_HOST: ([u8; 4], u16) = if cfg!(debug_assertions) {
     ([127, 0, 0, 1], 8080)
 } else {
     ([0, 0, 0, 0], 8080) // 0.0.0.0 because inside Docker container
 };
+pub const TLS_HOST: ([u8; 4], u16) = if cfg!(debug_assertions) {
+    ([127, 0, 0, 1], 8443)
+} else {
+    ([0, 0, 0, 0], 8443)
+};
+// time allowed to a client to complete the TLS handshake (in seconds)
+pub const TLS_HANDSHAKE_TIMEOUT: u64 = 10;
+pub const ACCEPT_RETRY_DELAY: u64 = 100; // ms to wait after a failed accept, e.g. out of file descriptors
+pub const REJECT_WRITE_TIMEOUT: u64 = 1; // seconds to write the 503 to a connection over max_connections
+pub const REJECT_RESPONSE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nRetry-After: 1\r\nConnection: close\r\n\r\n";
+
+/********* config.rs *********/
+pub const CONFIG_FILE: &str = "data/config.toml";
 
 /********* log.rs *********/
 pub const LOG_FILE: &str = "data/log.txt";
+pub const AUDIT_LOG_FILE: &str = "data/audit.txt"; // admin actions only
+pub const CSP_LOG_FILE: &str = "data/csp.txt"; // CSP violation reports only, rotated like LOG_FILE
+pub const LOG_MAX_SIZE: u64 = 10 * 1024 * 1024; // default rotation size of LOG_FILE (in bytes)
+pub const LOG_MAX_FILES: usize = 10; // default number of rotated LOG_FILE archives to keep
+pub const LOG_BUFFERED_LINES: usize = 256_000; // lines a file sink holds while its worker is busy, then drops
 
 /********* db.rs *********/
 pub const DB_FILE: &str = "data/db.txt";
 pub const DB_WRITE_INTERVAL: u64 = 1; // seconds
 
-// 1000*200*4 bytes = 781.25 kB upfront memory allocation
 pub const DB_INIT_NB_MSG: usize = 1000; // initial capacity for messages
-pub const DB_MAX_MSG_SIZE: usize = 4 * 200; // max size of each message (in bytes, *4 for UTF-8 encoding)
 
 /********* ws.rs *********/
 // Maximum number of messages a clients channel can hold
@@ -26,17 +40,74 @@ pub const WS_PING_INTERVAL: u64 = if cfg!(debug_assertions) { 5 } else { 60 };
 // Maximum number of users allowed in the WebSocket hub
 pub const WS_MAX_USERS: usize = if cfg!(debug_assertions) { 2 } else { 100 };
 
-/********* handler.rs *********/
-// urls served (seen from the client browser)
-pub const URL_JS: &str = env!("BUILD_URL_JS");
-pub const URL_CSS: &str = env!("BUILD_URL_CSS");
-pub const URL_ICON: &str = env!("BUILD_URL_ICON");
-pub const URL_BG: &str = env!("BUILD_URL_BG");
-
-// static files (paths relative to src/ build directory)
-pub static F_JS: &[u8] = include_bytes!("../templates/script.js");
-pub static F_ICON: &[u8] = include_bytes!("../templates/favicon.png");
-pub static F_SITEMAP: &[u8] = include_bytes!("../templates/sitemap.xml");
-pub static F_ROBOTS: &[u8] = include_bytes!("../templates/robots.txt");
-pub static F_CSS: &[u8] = include_bytes!("../target/user_dir/styles.css"); // CSS file is templated by build.rs
-pub static F_BG: &[u8] = include_bytes!("../templates/bg.webp");
+/********* proxy_protocol.rs *********/
+// time allowed to a peer to send its PROXY protocol header (in seconds)
+pub const PROXY_HEADER_TIMEOUT: u64 = 5;
+pub const PROXY_V2_MAX_LEN: usize = 4096; // longer v2 headers (addresses and TLVs) are refused
+
+/********* tls.rs *********/
+// interval between checks of the certificate files for changes (in seconds)
+pub const TLS_RELOAD_INTERVAL: u64 = 10;
+
+/********* admin.rs *********/
+pub const ADMIN_MAX_BODY_SIZE: usize = 4 * 1024; // max size of an admin request body (in bytes)
+pub const ADMIN_TOKEN_MIN_LENGTH: usize = 16; // shorter bearer tokens are refused at startup
+pub const ADMIN_AUTH_FAILURES_PER_SECOND: u32 = 1; // failed attempts a second per client IP, on average
+pub const ADMIN_AUTH_FAILURES_BURST: u32 = 5; // failed attempts allowed at once
+pub const ADMIN_MAX_VERIFICATIONS: usize = 4; // argon2 verifications at once, about 19 MiB each
+
+/********* health.rs *********/
+// the flush task is reported unhealthy after missing this many DB_WRITE_INTERVAL ticks
+pub const HEALTH_FLUSH_MAX_MISSED: u64 = 5;
+// timeout of the `webrs healthcheck` probe (in seconds)
+pub const HEALTH_PROBE_TIMEOUT: u64 = 3;
+
+/********* middleware.rs *********/
+pub const NONCE_LENGTH: usize = 32; // random bytes of the CSP nonce, base64 encoded
+pub const REQUEST_ID_MAX_LENGTH: usize = 64; // longer X-Request-Id headers are replaced
+
+/********* csp.rs *********/
+pub const CSP_REPORT_PATH: &str = "/csp-report";
+pub const CSP_REPORT_GROUP: &str = "csp-endpoint"; // name of the endpoint in Reporting-Endpoints
+pub const CSP_REPORT_MAX_BODY_SIZE: usize = 16 * 1024; // max size of a report body (in bytes)
+pub const CSP_REPORT_DEDUPE_WINDOW: u64 = 600; // seconds an identical violation is not logged again
+pub const CSP_REPORT_DEDUPE_MAX: usize = 4096; // distinct violations remembered
+
+/********* ratelimit.rs *********/
+pub const RATE_LIMIT_PRUNE_EVERY: u64 = 1024; // checks between two prunes of the full buckets
+
+/********* compress.rs *********/
+pub const COMPRESS_BROTLI_QUALITY: i32 = 5; // on the fly, build.rs uses the maximum (11)
+pub const COMPRESS_GZIP_LEVEL: u32 = 6; // on the fly, build.rs uses the maximum (9)
+
+/********* assets.rs *********/
+pub const DEV_TEMPLATES_DIR: &str = "templates"; // the layout source, part of the hash of the pages in dev mode
+pub const DEV_WATCH_INTERVAL: u64 = 500; // ms between checks for modified assets and content
+
+/********* errors.rs *********/
+// API and WebSocket routes, their errors are JSON unless the client prefers HTML
+pub const JSON_ERROR_PREFIXES: &[&str] = &["/admin", "/ws", "/events", "/csp-report", "/metrics"];
+
+/********* posts.rs *********/
+pub const POSTS_PATH: &str = "/posts"; // content pages under it with a date are posts, listed there
+pub const POSTS_ATOM_PATH: &str = "/posts/feed.atom";
+pub const POSTS_RSS_PATH: &str = "/posts/feed.rss";
+pub const POSTS_FEED_ENTRIES: usize = 20; // most recent posts in the feeds
+
+/********* feed.rs *********/
+pub const FEED_PATH: &str = "/feed.atom";
+pub const FEED_ENTRIES: usize = 50; // most recent messages in the feed
+pub const FEED_TITLE_LENGTH: usize = 60; // characters of a message in the title of its entry
+
+/********* events.rs *********/
+pub const EVENTS_PATH: &str = "/events";
+// Maximum number of events a subscriber's channel can hold, a slower one is dropped
+pub const EVENTS_BUFF_EVENTS: usize = 32;
+// Message events kept for the clients resuming with Last-Event-ID
+pub const EVENTS_BACKLOG: usize = 100;
+// Seconds without an event before a keep-alive comment
+pub const EVENTS_KEEP_ALIVE: u64 = if cfg!(debug_assertions) { 5 } else { 15 };
+// Reconnection delay given to the clients (in ms)
+pub const EVENTS_RETRY: u64 = 3000;
+// Maximum number of open event streams
+pub const EVENTS_MAX_SUBSCRIBERS: usize = if cfg!(debug_assertions) { 2 } else { 100 };
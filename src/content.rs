@@ -0,0 +1,240 @@
+/*  Content pages
+    - Shared with build.rs (included with #[path]) like manifest.rs, release builds embed the
+      pages build.rs compiles, dev mode compiles CONTENT_DIR itself and on every change
+    - A page is a Markdown file of CONTENT_DIR with a front matter between "---" lines, served
+      at its path without the extension ("about.md" at /about, "notes/index.md" at /notes)
+    - The Markdown may refer to assets as {{asset:<name>}}, like the templates
+    - Drafts are compiled in dev mode only, build.rs leaves them out
+*/
+use pulldown_cmark::{Options, Parser, html};
+use sha2::{Digest, Sha256};
+
+pub const CONTENT_DIR: &str = "content";
+
+// page as generated by build.rs into the embedded list, see Page
+#[derive(Debug)]
+pub struct PageEntry {
+    pub path: &'static str,
+    pub title: &'static str,
+    pub description: &'static str,
+    pub date: Option<&'static str>,
+    pub lastmod: &'static str,
+    pub tags: &'static [&'static str],
+    pub html: &'static str,
+    pub hash: &'static str,
+}
+
+// a page once compiled, its Markdown turned into the HTML the layout wraps
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub path: String,         // served at
+    pub title: String,        // <title> and the heading of the layout
+    pub description: String,  // <meta name="description">
+    pub date: Option<String>, // YYYY-MM-DD, published, posts are sorted by it
+    pub lastmod: String,      // YYYY-MM-DD, for the sitemap and the feeds
+    pub tags: Vec<String>,
+    pub draft: bool,
+    pub html: String,
+    pub hash: String, // sha256 of the layout and the html, hex
+}
+
+impl From<&PageEntry> for Page {
+    fn from(entry: &PageEntry) -> Self {
+        Self {
+            path: entry.path.to_string(),
+            title: entry.title.to_string(),
+            description: entry.description.to_string(),
+            date: entry.date.map(str::to_string),
+            lastmod: entry.lastmod.to_string(),
+            tags: entry.tags.iter().map(|tag| tag.to_string()).collect(),
+            draft: false,
+            html: entry.html.to_string(),
+            hash: entry.hash.to_string(),
+        }
+    }
+}
+
+// what a page says about itself
+#[derive(Debug, Default)]
+struct FrontMatter {
+    title: String,
+    description: String,
+    date: Option<String>,    // YYYY-MM-DD, first published
+    updated: Option<String>, // YYYY-MM-DD, last changed
+    tags: Vec<String>,       // "[a, b]" or "a, b"
+    draft: bool,
+}
+
+// true for the files of CONTENT_DIR that are pages
+pub fn is_page(name: &str) -> bool {
+    name.ends_with(".md")
+}
+
+// path `name` is served at
+pub fn path_of(name: &str) -> String {
+    let name = name.strip_suffix(".md").unwrap_or(name);
+    let name = name
+        .strip_suffix("index")
+        .filter(|rest| rest.is_empty() || rest.ends_with('/'))
+        .unwrap_or(name);
+    format!("/{}", name.trim_end_matches('/'))
+}
+
+// the front matter and the Markdown after it
+fn split(source: &str) -> Result<(FrontMatter, &str), String> {
+    let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+    let rest = source
+        .strip_prefix("---\n")
+        .or_else(|| source.strip_prefix("---\r\n"))
+        .ok_or("no front matter, the file must start with a \"---\" line")?;
+    let end = rest
+        .find("\n---")
+        .ok_or("unclosed front matter, no closing \"---\" line")?;
+    let body = rest[end + 4..].trim_start_matches(['\r', '\n']);
+
+    let mut front = FrontMatter::default();
+    for line in rest[..end].lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("front matter line without a key: {line:?}"))?;
+        let value = unquote(value.trim()).to_string();
+        match key.trim() {
+            "title" => front.title = value,
+            "description" => front.description = value,
+            "date" => front.date = Some(date(&value)?),
+            "updated" => front.updated = Some(date(&value)?),
+            "tags" => front.tags = tags(&value)?,
+            "draft" => {
+                front.draft = value
+                    .parse()
+                    .map_err(|_| format!("invalid draft '{value}', expected true or false"))?
+            }
+            key => return Err(format!("unknown front matter key '{key}'")),
+        }
+    }
+    if front.title.is_empty() {
+        return Err("the front matter has no title".to_string());
+    }
+    Ok((front, body))
+}
+
+fn unquote(value: &str) -> &str {
+    ['"', '\'']
+        .iter()
+        .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+        .unwrap_or(value)
+}
+
+// tags are part of urls, /tags/<tag>, so they are kept to lowercase letters, digits and "-"
+fn tags(value: &str) -> Result<Vec<String>, String> {
+    let list = value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .unwrap_or(value);
+    let mut tags = Vec::new();
+    for tag in list.split(',').map(|tag| unquote(tag.trim())) {
+        if tag.is_empty() {
+            continue;
+        }
+        if !tag
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        {
+            return Err(format!(
+                "invalid tag '{tag}', expected lowercase letters, digits and '-'"
+            ));
+        }
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    Ok(tags)
+}
+
+// `value` if it is a YYYY-MM-DD date
+fn date(value: &str) -> Result<String, String> {
+    let parts: Vec<&str> = value.split('-').collect();
+    let valid = matches!(parts.as_slice(), [y, m, d]
+        if y.len() == 4 && m.len() == 2 && d.len() == 2
+            && parts.iter().all(|p| p.bytes().all(|b| b.is_ascii_digit()))
+            && (1..=12).contains(&m.parse::<u32>().unwrap_or(0))
+            && (1..=31).contains(&d.parse::<u32>().unwrap_or(0)));
+    if valid {
+        Ok(value.to_string())
+    } else {
+        Err(format!("invalid date '{value}', expected YYYY-MM-DD"))
+    }
+}
+
+// unix time of the start of a YYYY-MM-DD day, in UTC
+pub fn timestamp_of(date: &str) -> u64 {
+    let mut parts = date.split('-').map(|p| p.parse::<i64>().unwrap_or(1));
+    let (year, month, day) = (
+        parts.next().unwrap_or(1970),
+        parts.next().unwrap_or(1),
+        parts.next().unwrap_or(1),
+    );
+    // civil date to days, the inverse of date_of
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    (days.max(0) as u64) * 86400
+}
+
+// YYYY-MM-DD of a unix time, in UTC
+pub fn date_of(secs: u64) -> String {
+    // days to civil date, from Howard Hinnant's date algorithms
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+// compiles the page `name` of CONTENT_DIR, `layout` is the source of the layout it is
+// rendered in and `modified` (unix time) its lastmod when the front matter has no date
+pub fn compile(name: &str, source: &str, layout: &str, modified: u64) -> Result<Page, String> {
+    let (front, markdown) = split(source)?;
+    let path = path_of(name);
+    if path == "/" {
+        return Err("the homepage is the guestbook, it cannot be a page".to_string());
+    }
+
+    let mut html = String::with_capacity(markdown.len() * 3 / 2);
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_HEADING_ATTRIBUTES;
+    html::push_html(&mut html, Parser::new_ext(markdown, options));
+
+    let mut hasher = Sha256::new();
+    hasher.update(layout.as_bytes());
+    hasher.update(html.as_bytes());
+
+    Ok(Page {
+        path,
+        title: front.title,
+        description: front.description,
+        lastmod: front
+            .updated
+            .or_else(|| front.date.clone())
+            .unwrap_or_else(|| date_of(modified)),
+        date: front.date,
+        tags: front.tags,
+        draft: front.draft,
+        html,
+        hash: format!("{:x}", hasher.finalize()),
+    })
+}
@@ -1,3 +1,4 @@
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use base64::{Engine as _, engine::general_purpose};
 use rand::RngCore;
 
@@ -6,3 +7,27 @@ pub fn generate_nonce_base64(len: usize) -> String {
     rand::rng().fill_bytes(&mut bytes);
     general_purpose::STANDARD.encode(bytes)
 }
+
+// short random id used to correlate the log lines of one request
+pub fn generate_request_id() -> String {
+    format!("{:016x}", rand::rng().next_u64())
+}
+
+// compares two secrets without leaking the position of the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// verifies a password against an argon2 PHC string, this is slow on purpose
+// so it should be called from a blocking task
+pub fn verify_password(password: &str, phc_hash: &str) -> bool {
+    match PasswordHash::new(phc_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,183 @@
+/*  CSP violation reports
+    - POST /csp-report takes the legacy format (application/csp-report, {"csp-report": {...}})
+      and the Reporting API one (application/reports+json, a list of reports of any type,
+      only "csp-violation" ones are kept)
+    - Reports are rate limited per IP and de-duplicated over CSP_REPORT_DEDUPE_WINDOW, the
+      others are written to CSP_LOG_FILE and counted in webrs_csp_reports_total
+*/
+use crate::config;
+use crate::constants;
+use crate::handler::RequestBody;
+use crate::metrics;
+use crate::middleware;
+use crate::ratelimit::RateLimiter;
+use crate::router::HandlerResult;
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// events logged with this target go to CSP_LOG_FILE instead of the regular sinks
+pub const CSP_TARGET: &str = "csp";
+
+static LIMITER: Lazy<RateLimiter> = Lazy::new(|| {
+    let cfg = &config::get().csp_report;
+    RateLimiter::new(cfg.per_second, cfg.burst)
+});
+
+// when each violation was last logged
+static SEEN: Lazy<DashMap<Violation, Instant>> = Lazy::new(DashMap::new);
+
+// what a report says, in either format
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Violation {
+    document: String,
+    blocked: String,
+    directive: String,
+    source: String,
+    line: u64,
+    disposition: String, // "enforce" or "report"
+}
+
+impl Violation {
+    // reads the fields of a report body, `names` are the keys of its format
+    fn from_body(body: &Value, names: [&str; 6]) -> Option<Self> {
+        let text = |key: &str| {
+            body.get(key)
+                .and_then(Value::as_str)
+                .unwrap_or("")
+                .to_string()
+        };
+        let violation = Self {
+            document: text(names[0]),
+            blocked: text(names[1]),
+            directive: text(names[2]),
+            source: text(names[3]),
+            line: body.get(names[4]).and_then(Value::as_u64).unwrap_or(0),
+            disposition: text(names[5]),
+        };
+        (!violation.directive.is_empty()).then_some(violation)
+    }
+}
+
+// the violations of a report body, legacy or Reporting API
+fn parse(body: &[u8]) -> Option<Vec<Violation>> {
+    const LEGACY: [&str; 6] = [
+        "document-uri",
+        "blocked-uri",
+        "violated-directive",
+        "source-file",
+        "line-number",
+        "disposition",
+    ];
+    const REPORTING: [&str; 6] = [
+        "documentURL",
+        "blockedURL",
+        "effectiveDirective",
+        "sourceFile",
+        "lineNumber",
+        "disposition",
+    ];
+
+    match serde_json::from_slice::<Value>(body).ok()? {
+        Value::Object(report) => {
+            let body = report.get("csp-report")?;
+            // the effective directive is the one that was violated, newer browsers send both
+            let mut violation = Violation::from_body(body, LEGACY)?;
+            if let Some(effective) = body.get("effective-directive").and_then(Value::as_str) {
+                violation.directive = effective.to_string();
+            }
+            Some(vec![violation])
+        }
+        Value::Array(reports) => Some(
+            reports
+                .iter()
+                .filter(|r| r.get("type").and_then(Value::as_str) == Some("csp-violation"))
+                .filter_map(|r| Violation::from_body(r.get("body")?, REPORTING))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+// true the first time `violation` is seen in CSP_REPORT_DEDUPE_WINDOW
+fn is_new(violation: &Violation) -> bool {
+    let now = Instant::now();
+    let window = Duration::from_secs(constants::CSP_REPORT_DEDUPE_WINDOW);
+
+    if SEEN.len() >= constants::CSP_REPORT_DEDUPE_MAX {
+        SEEN.retain(|_, at| now.duration_since(*at) < window);
+    }
+    // the guard of get() is dropped before inserting, the shard is locked meanwhile
+    let recent = SEEN
+        .get(violation)
+        .is_some_and(|at| now.duration_since(*at) < window);
+    if recent {
+        return false;
+    }
+    // still full of recent ones, better log a duplicate than grow without end
+    if SEEN.len() < constants::CSP_REPORT_DEDUPE_MAX {
+        SEEN.insert(violation.clone(), now);
+    }
+    true
+}
+
+fn respond(status: StatusCode) -> HandlerResult {
+    let mut res = Response::new(Full::new(Bytes::new()));
+    *res.status_mut() = status;
+    Ok(res)
+}
+
+pub async fn handle_report(req: Request<RequestBody>) -> HandlerResult {
+    let ip = middleware::client_ip(&req);
+    if !LIMITER.check(ip) {
+        metrics::RATE_LIMITED.fetch_add(1, Ordering::Relaxed);
+        info!(status = 429, "CSP report rate limited");
+        return respond(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let user_agent = req
+        .headers()
+        .get("User-Agent")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let body = match Limited::new(req.into_body(), constants::CSP_REPORT_MAX_BODY_SIZE)
+        .collect()
+        .await
+    {
+        Ok(collected) => collected.to_bytes(),
+        Err(_) => {
+            info!(status = 413, "CSP report too large");
+            return respond(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    };
+
+    let Some(violations) = parse(&body) else {
+        info!(status = 400, "Invalid CSP report");
+        return respond(StatusCode::BAD_REQUEST);
+    };
+
+    for violation in violations.iter().filter(|v| is_new(v)) {
+        metrics::CSP_REPORTS.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            target: CSP_TARGET,
+            ip = %ip,
+            document = violation.document,
+            blocked = violation.blocked,
+            directive = violation.directive,
+            source = violation.source,
+            line = violation.line,
+            disposition = violation.disposition,
+            user_agent,
+            "CSP violation"
+        );
+    }
+    respond(StatusCode::NO_CONTENT)
+}
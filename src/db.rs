@@ -1,45 +1,140 @@
 /*  TODO: can be improved
     - Might look into more efficient data structures for messages
-    - May want to store messages in json to add metadata
     - Lock times of GLOBAL_MESSAGES may be too long in render or initialize functions
 */
+use crate::assets;
 use crate::constants;
+use crate::feed;
+use crate::metrics;
 use once_cell::sync::Lazy;
-use sailfish::{RenderError, TemplateSimple};
+use sailfish::RenderError;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::RwLock;
-use tracing::error;
-#[cfg(debug_assertions)]
-use tracing::info;
-
-#[derive(TemplateSimple)]
-#[template(path = "../target/user_dir/index.html")] // pre-templated by build.rs
-#[template(rm_whitespace = true)]
-struct Template<'a> {
-    pub nbusers: &'a usize,
-    pub nonce: &'a str,
-    pub messages: &'a Vec<String>,
-}
-
-static GLOBAL_MESSAGES: Lazy<Arc<RwLock<Vec<String>>>> = Lazy::new(|| {
-    let mut vec = Vec::with_capacity(constants::DB_INIT_NB_MSG);
-    for _ in 0..constants::DB_INIT_NB_MSG {
-        vec.push(String::with_capacity(constants::DB_MAX_MSG_SIZE));
-    }
-    Arc::new(RwLock::new(vec))
-});
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, error, info};
+
+// a stored message, one JSON line of DB_FILE
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Message {
+    pub id: u64, // unique among the stored messages, unlike the index not moved by a deletion
+    pub at: u64, // unix time it was posted
+    pub text: String,
+}
+
+static GLOBAL_MESSAGES: Lazy<Arc<RwLock<Vec<Message>>>> =
+    Lazy::new(|| Arc::new(RwLock::new(Vec::with_capacity(constants::DB_INIT_NB_MSG))));
+
+// id of the next message, one more than the highest stored
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// number of messages at the start of GLOBAL_MESSAGES that are already in DB_FILE
+static FLUSHED: Lazy<Mutex<usize>> = Lazy::new(|| Mutex::new(0));
+
+// set when DB_FILE no longer matches GLOBAL_MESSAGES, the next flush rewrites it
+static DIRTY: AtomicBool = AtomicBool::new(false);
+
+// state of the database as seen by the readiness probe
+static LOADED: AtomicBool = AtomicBool::new(false);
+static LAST_FLUSH_OK: AtomicBool = AtomicBool::new(true);
+static LAST_FLUSH_AT: AtomicU64 = AtomicU64::new(0); // unix timestamp in seconds
+
+// bumped by every message added or deleted, it restarts with the process hence STARTED in
+// the ETag
+static REVISION: AtomicU64 = AtomicU64::new(0);
+static STARTED: Lazy<u64> = Lazy::new(unix_now);
 
-pub async fn add_message(msg: String) {
+// unix time of the last change to the messages, the mtime of DB_FILE once loaded
+static CHANGED_AT: AtomicU64 = AtomicU64::new(0);
+
+pub async fn add_message(text: String) {
     let mut messages = GLOBAL_MESSAGES.write().await;
-    messages.push(msg);
+    messages.push(Message {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        at: unix_now(),
+        text,
+    });
+    changed();
+}
+
+// a new revision, the cached feed no longer matches it
+fn changed() {
+    REVISION.fetch_add(1, Ordering::Release);
+    CHANGED_AT.store(unix_now(), Ordering::Release);
+    feed::invalidate();
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[inline(always)]
+pub fn is_loaded() -> bool {
+    LOADED.load(Ordering::Acquire)
+}
+
+// the flush task is healthy if its last run succeeded and it ran recently
+pub fn is_flush_healthy() -> bool {
+    let last = LAST_FLUSH_AT.load(Ordering::Acquire);
+    let max_age = constants::DB_WRITE_INTERVAL * constants::HEALTH_FLUSH_MAX_MISSED;
+    // before the first tick the task is given the benefit of the doubt
+    let recent = last == 0 || unix_now().saturating_sub(last) <= max_age;
+    LAST_FLUSH_OK.load(Ordering::Acquire) && recent
+}
+
+pub async fn message_count() -> usize {
+    GLOBAL_MESSAGES.read().await.len()
+}
+
+pub async fn list_messages() -> Vec<Message> {
+    GLOBAL_MESSAGES.read().await.clone()
+}
+
+// removes the message with `id`, the file is rewritten on the next flush
+pub async fn delete_message(id: u64) -> Option<Message> {
+    let mut messages = GLOBAL_MESSAGES.write().await;
+    let index = messages.iter().position(|msg| msg.id == id)?;
+    DIRTY.store(true, Ordering::Release);
+    let removed = messages.remove(index);
+    changed();
+    Some(removed)
+}
+
+// the `n` most recent messages, the newest first
+pub async fn recent_messages(n: usize) -> Vec<Message> {
+    let messages = GLOBAL_MESSAGES.read().await;
+    let start = messages.len().saturating_sub(n);
+    messages[start..].iter().rev().cloned().collect()
+}
+
+// changes with every message added or deleted, taken before reading the messages so it is
+// never newer than what is built from them
+pub fn version() -> String {
+    format!("{:x}-{}", *STARTED, REVISION.load(Ordering::Acquire))
+}
+
+pub fn changed_at() -> SystemTime {
+    UNIX_EPOCH + std::time::Duration::from_secs(CHANGED_AT.load(Ordering::Acquire))
+}
+
+// weak ETag of the homepage, the same messages and template render the same page but for
+// the user count and the nonce, taken before rendering so it is never newer than the page
+pub fn index_etag() -> String {
+    format!("W/\"{}-{}\"", version(), assets::get().generation())
 }
 
 // TODO: optimize by not having to do a deep copy of the template each time we return the result
 // rather render once to a buffer allocated in the calling function
 pub async fn render(nbusers: &usize, nonce: &str) -> Result<String, RenderError> {
+    let start = Instant::now();
+
     // might lock a bit too long but does not copy
     let store = GLOBAL_MESSAGES.clone();
     let messages = store.read().await;
@@ -50,32 +145,46 @@ pub async fn render(nbusers: &usize, nonce: &str) -> Result<String, RenderError>
     //    guard.clone() // fast and avoids holding lock during render
     //};
 
-    let template = Template {
-        nbusers,
-        nonce,
-        messages: &messages,
-    };
-
-    template.render_once()
+    let result = assets::get().render_index(nbusers, nonce, &messages);
+    metrics::RENDER_LATENCY.observe(start.elapsed());
+    result
 }
 
 pub async fn initialize() -> Result<(), std::io::Error> {
-    // can be used by one task only
-    let mut count_prev;
+    let modified = fs::metadata(constants::DB_FILE)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(unix_now, |d| d.as_secs());
 
     // read from file and initialize GLOBAL_MESSAGES
     {
         let mut messages = GLOBAL_MESSAGES.write().await;
         messages.clear();
 
-        // read from file
+        // messages without an id are numbered in order and dated by the file, the first flush
+        // rewrites them with the ones they got
         let contents = fs::read_to_string(constants::DB_FILE).await?;
+        let mut next_id = 1;
         for line in contents.lines() {
-            messages.push(line.to_string());
+            let msg = serde_json::from_str::<Message>(line).unwrap_or_else(|_| {
+                DIRTY.store(true, Ordering::Release);
+                Message {
+                    id: next_id,
+                    at: modified,
+                    text: line.to_string(),
+                }
+            });
+            next_id = next_id.max(msg.id + 1);
+            messages.push(msg);
         }
+        NEXT_ID.store(next_id, Ordering::Relaxed);
 
-        count_prev = messages.len();
+        *FLUSHED.lock().await = messages.len();
     }
+    CHANGED_AT.store(modified, Ordering::Release);
+    LOADED.store(true, Ordering::Release);
 
     // spawn task to write to DB_FILE every 1 second
     tokio::spawn(async move {
@@ -85,39 +194,120 @@ pub async fn initialize() -> Result<(), std::io::Error> {
             ))
             .await;
 
-            let messages = GLOBAL_MESSAGES.read().await;
-            let count_current = messages.len();
-            if count_prev < count_current {
-                let new_messages = &messages[count_prev..count_current];
-
-                // Open the file in append mode
-                if let Ok(mut file) = OpenOptions::new()
-                    .append(true)
-                    .open(constants::DB_FILE)
-                    .await
-                {
-                    let buffer = new_messages.join("\n") + "\n";
-                    if let Err(e) = file.write_all(buffer.as_bytes()).await {
-                        error!("[D] Failed to write messages to file: {}", e);
-                    }
-                    #[cfg(debug_assertions)]
-                    info!("[D] Wrote {} messages to file", count_current - count_prev);
-
-                    count_prev = count_current;
-                } else {
-                    error!(
-                        "[D] Failed to open file for writing: {}",
-                        constants::DB_FILE
-                    );
-                }
-            } else {
-                #[cfg(debug_assertions)]
-                info!(
-                    "[D] No new messages to write to file, current count: {}",
-                    count_current
-                );
+            let result = flush(false).await;
+            LAST_FLUSH_OK.store(result.is_ok(), Ordering::Release);
+            LAST_FLUSH_AT.store(unix_now(), Ordering::Release);
+            if let Err(e) = result {
+                error!(error = %e, "Failed to write messages to file");
             }
         }
     });
     Ok(())
 }
+
+// Writes the messages that are not on disk yet to DB_FILE and returns how many were written.
+// When compact is set (or a message was deleted since the last flush) the whole file
+// is rewritten through a temporary file instead of being appended to.
+pub async fn flush(compact: bool) -> Result<usize, std::io::Error> {
+    let start = Instant::now();
+    let result = write_messages(compact).await;
+    match result {
+        Ok(0) => {} // nothing to write, not worth a sample
+        Ok(_) => metrics::DB_FLUSH_LATENCY.observe(start.elapsed()),
+        Err(_) => metrics::inc(&metrics::DB_FLUSH_FAILURES),
+    }
+    result
+}
+
+// `messages` as lines of DB_FILE
+fn lines(messages: &[Message]) -> String {
+    let mut buffer = String::new();
+    for msg in messages {
+        // a struct of numbers and a string always serializes
+        buffer.push_str(&serde_json::to_string(msg).unwrap_or_default());
+        buffer.push('\n');
+    }
+    buffer
+}
+
+async fn write_messages(compact: bool) -> Result<usize, std::io::Error> {
+    // only one flush at a time, the lock also guards the on-disk count
+    let mut count_prev = FLUSHED.lock().await;
+
+    let messages = GLOBAL_MESSAGES.read().await;
+    let count_current = messages.len();
+
+    if compact || DIRTY.swap(false, Ordering::AcqRel) {
+        let tmp_file = format!("{}.tmp", constants::DB_FILE);
+        let buffer = lines(&messages);
+
+        fs::write(&tmp_file, buffer.as_bytes()).await?;
+        fs::rename(&tmp_file, constants::DB_FILE).await?;
+
+        info!(messages = count_current, "Compacted file");
+
+        *count_prev = count_current;
+        return Ok(count_current);
+    }
+
+    if *count_prev < count_current {
+        let new_messages = &messages[*count_prev..count_current];
+
+        // Open the file in append mode
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(constants::DB_FILE)
+            .await?;
+
+        let buffer = lines(new_messages);
+        file.write_all(buffer.as_bytes()).await?;
+
+        debug!(
+            messages = count_current - *count_prev,
+            "Wrote messages to file"
+        );
+
+        let written = count_current - *count_prev;
+        *count_prev = count_current;
+        Ok(written)
+    } else {
+        debug!(messages = count_current, "No new messages to write to file");
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_line_per_message() {
+        let messages = [
+            Message {
+                id: 1,
+                at: 1_749_810_030,
+                text: "hello".to_string(),
+            },
+            Message {
+                id: 7,
+                at: 1_749_810_031,
+                text: "two\nlines, \"quoted\"".to_string(),
+            },
+        ];
+        let written = lines(&messages);
+        assert_eq!(written.lines().count(), 2);
+        let read: Vec<Message> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(read, messages);
+    }
+
+    #[test]
+    fn plain_lines_are_not_records() {
+        // written before messages had an id, initialize numbers them
+        for line in ["hello", "{\"text\":\"no id\"}", "42"] {
+            assert!(serde_json::from_str::<Message>(line).is_err(), "{line}");
+        }
+    }
+}
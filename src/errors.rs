@@ -0,0 +1,146 @@
+/*  Error pages
+    - Error responses without a body get one: the status, the request id and a link home,
+      rendered from templates/error.html, or JSON for the clients that prefer it
+    - Accept decides between text/html and application/json, when it does not (no Accept,
+      only wildcards or equal q-values) API and WebSocket routes get JSON and the others HTML
+*/
+use crate::accept;
+use crate::assets;
+use crate::constants;
+
+use bytes::Bytes;
+use hyper::StatusCode;
+use hyper::header::{ACCEPT, HeaderMap};
+use tracing::error;
+
+// q-value of `media` in an Accept header, the most specific range wins, None if not accepted
+// by any of them
+fn q_value(accept: &str, media: &str) -> Option<f32> {
+    let (kind, _) = media.split_once('/').unwrap_or((media, ""));
+    let mut best: Option<(u8, f32)> = None; // (specificity, q)
+
+    for (range, q) in accept::items(accept) {
+        let specificity = if range.eq_ignore_ascii_case(media) {
+            3
+        } else if range
+            .strip_suffix("/*")
+            .is_some_and(|k| k.eq_ignore_ascii_case(kind))
+        {
+            2
+        } else if range == "*/*" {
+            1
+        } else {
+            continue;
+        };
+        if best.is_none_or(|(s, _)| specificity > s) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map(|(_, q)| q)
+}
+
+// true if the error of a request to `path` is better sent as JSON
+pub fn prefers_json(headers: &HeaderMap, path: &str) -> bool {
+    let api = constants::JSON_ERROR_PREFIXES.iter().any(|prefix| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    });
+    let Some(accept) = headers.get(ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return api;
+    };
+
+    let html = q_value(accept, "text/html").unwrap_or(0.0);
+    let json = q_value(accept, "application/json").unwrap_or(0.0);
+    if json == html { api } else { json > html }
+}
+
+// content type and body of the error page of `status`
+pub fn body(status: StatusCode, request_id: &str, json: bool) -> (&'static str, Bytes) {
+    let reason = status.canonical_reason().unwrap_or("Error");
+    if json {
+        let body = serde_json::json!({
+            "error": reason,
+            "status": status.as_u16(),
+            "request_id": request_id,
+        });
+        return (
+            "application/json",
+            Bytes::from(serde_json::to_vec(&body).unwrap_or_default()),
+        );
+    }
+
+    match assets::get().render_error(status.as_u16(), reason, request_id) {
+        Ok(page) => ("text/html; charset=utf-8", Bytes::from(page)),
+        Err(e) => {
+            error!(error = %e, "Failed to render the error page");
+            (
+                "text/plain; charset=utf-8",
+                Bytes::from(format!("{} {reason}\n", status.as_u16())),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn without_accept_api_routes_get_json() {
+        let none = HeaderMap::new();
+        assert!(prefers_json(&none, "/admin"));
+        assert!(prefers_json(&none, "/admin/messages/3"));
+        assert!(prefers_json(&none, "/events"));
+        assert!(!prefers_json(&none, "/"));
+        assert!(!prefers_json(&none, "/administrator"));
+    }
+
+    #[test]
+    fn accept_decides_when_it_prefers_one() {
+        assert!(prefers_json(&accept("application/json"), "/"));
+        assert!(!prefers_json(&accept("text/html"), "/admin"));
+        assert!(!prefers_json(
+            &accept("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+            "/admin/users"
+        ));
+        assert!(prefers_json(
+            &accept("text/html;q=0.5, application/json"),
+            "/"
+        ));
+        assert!(prefers_json(&accept("text/html;q=0, */*"), "/"));
+    }
+
+    #[test]
+    fn the_most_specific_range_wins() {
+        // text/html is excluded even though text/* and */* accept it
+        assert!(prefers_json(
+            &accept("text/*, */*;q=0.5, text/html;q=0"),
+            "/"
+        ));
+        assert!(!prefers_json(
+            &accept("application/*;q=0.2, text/*"),
+            "/admin"
+        ));
+    }
+
+    #[test]
+    fn ties_fall_back_to_the_route() {
+        for value in [
+            "*/*",
+            "text/html, application/json",
+            "text/*;q=0.5, application/*;q=0.5",
+        ] {
+            assert!(prefers_json(&accept(value), "/ws"), "{value}");
+            assert!(!prefers_json(&accept(value), "/about"), "{value}");
+        }
+        assert!(prefers_json(&accept("image/png"), "/metrics"));
+        assert!(!prefers_json(&accept("image/png"), "/"));
+    }
+}
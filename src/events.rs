@@ -0,0 +1,349 @@
+/*  Server-Sent Events, the live updates for clients that cannot open a WebSocket
+    - GET /events streams what ws.rs broadcasts as text/event-stream, read only: every
+      event is unnamed and its data is the JSON message a WebSocket user gets
+    - message events have an id, a client reconnecting with Last-Event-ID is sent the ones
+      it missed among the last EVENTS_BACKLOG. Ids restart with the process, the id of a
+      previous one is not resumed
+    - a comment every EVENTS_KEEP_ALIVE seconds keeps proxies from closing a quiet stream
+    - the router only deals in complete bodies, the route answers with the headers and an
+      EventStream in the extensions that handle_request turns into the body. The body keeps
+      the request in flight, an open stream is not an idle connection
+*/
+use crate::activity::InFlight;
+use crate::constants;
+use crate::ws;
+
+use bytes::Bytes;
+use hyper::body::{Body, Frame};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time::{self, Duration, Instant, Interval};
+use tracing::{debug, info};
+
+type SubscriberId = usize;
+
+struct Hub {
+    next_id: u64,                                      // id of the next message event
+    backlog: VecDeque<(u64, Bytes)>,                   // last message events, the oldest first
+    subscribers: HashMap<SubscriberId, Sender<Bytes>>, // channels to the event streams
+}
+
+impl Hub {
+    fn new() -> Self {
+        Hub {
+            next_id: 1,
+            backlog: VecDeque::with_capacity(constants::EVENTS_BACKLOG),
+            subscribers: HashMap::new(),
+        }
+    }
+
+    // sends an event to every subscriber, messages are kept for resumption. A subscriber
+    // whose channel is full is dropped, its client resumes from what it received
+    fn publish(&mut self, json: &str, resumable: bool) {
+        let event = if resumable {
+            let n = self.next_id;
+            self.next_id += 1;
+            let event = event(Some(n), json);
+            if self.backlog.len() == constants::EVENTS_BACKLOG {
+                self.backlog.pop_front();
+            }
+            self.backlog.push_back((n, event.clone()));
+            event
+        } else {
+            event(None, json)
+        };
+        self.subscribers.retain(|id, tx| {
+            let sent = tx.try_send(event.clone()).is_ok();
+            if !sent {
+                debug!(subscriber = id, "Dropped event subscriber");
+            }
+            sent
+        });
+    }
+
+    // the channel of subscriber `id` and its first frame, the retry delay and the message
+    // events after last_event_id, None if the hub is full
+    fn subscribe(
+        &mut self,
+        id: SubscriberId,
+        last_event_id: Option<&str>,
+    ) -> Option<(Receiver<Bytes>, Bytes)> {
+        if self.subscribers.len() >= constants::EVENTS_MAX_SUBSCRIBERS {
+            return None;
+        }
+
+        let mut replay = format!("retry: {}\n\n", constants::EVENTS_RETRY).into_bytes();
+        if let Some(last) = last_event_id.and_then(parse_event_id) {
+            for (_, event) in self.backlog.iter().filter(|(n, _)| *n > last) {
+                replay.extend_from_slice(event);
+            }
+        }
+
+        let (tx, rx) = mpsc::channel(constants::EVENTS_BUFF_EVENTS);
+        self.subscribers.insert(id, tx);
+        Some((rx, Bytes::from(replay)))
+    }
+}
+
+// one lock for the backlog and the subscribers, a new subscriber gets every event once
+static HUB: Lazy<Mutex<Hub>> = Lazy::new(|| Mutex::new(Hub::new()));
+
+static NEXT_SUBSCRIBER: AtomicUsize = AtomicUsize::new(1);
+
+// start of the process, the first part of the event ids
+static EPOCH: Lazy<u64> = Lazy::new(|| {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+});
+
+const KEEP_ALIVE: &[u8] = b": keep-alive\n\n";
+
+// a subscriber, removed from the hub when dropped with the body of its stream
+pub struct Subscription {
+    id: SubscriberId,
+    rx: Receiver<Bytes>,
+    replay: Option<Bytes>, // retry delay and the missed events, the first frame
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        HUB.lock().unwrap().subscribers.remove(&self.id);
+        let nb_users = ws::broadcast_user_count();
+        info!(
+            subscriber = self.id,
+            users = nb_users,
+            "Event stream closed"
+        );
+    }
+}
+
+// extensions must be Clone, the subscription is taken once by handle_request
+#[derive(Clone)]
+pub struct EventStream(Arc<Mutex<Option<Subscription>>>);
+
+impl EventStream {
+    pub fn take(&self) -> Option<Subscription> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl From<Subscription> for EventStream {
+    fn from(subscription: Subscription) -> Self {
+        EventStream(Arc::new(Mutex::new(Some(subscription))))
+    }
+}
+
+#[inline(always)]
+pub fn subscriber_count() -> usize {
+    HUB.lock().unwrap().subscribers.len()
+}
+
+fn event_id(n: u64) -> String {
+    format!("{:x}-{}", *EPOCH, n)
+}
+
+// n of an id sent by this process
+fn parse_event_id(id: &str) -> Option<u64> {
+    let (epoch, n) = id.trim().split_once('-')?;
+    (u64::from_str_radix(epoch, 16).ok()? == *EPOCH)
+        .then(|| n.parse().ok())
+        .flatten()
+}
+
+// serialized JSON is a single line, it fits in one data field
+fn event(id: Option<u64>, json: &str) -> Bytes {
+    let mut out = String::with_capacity(json.len() + 32);
+    if let Some(n) = id {
+        let _ = writeln!(out, "id: {}", event_id(n));
+    }
+    let _ = write!(out, "data: {json}\n\n");
+    Bytes::from(out)
+}
+
+// sends a broadcast of ws.rs to every subscriber
+pub fn publish(json: &str, resumable: bool) {
+    HUB.lock().unwrap().publish(json, resumable);
+}
+
+// a new subscriber with the message events after last_event_id, none if the hub is full
+pub fn subscribe(last_event_id: Option<&str>) -> Option<Subscription> {
+    let id = NEXT_SUBSCRIBER.fetch_add(1, Ordering::Relaxed);
+    let (rx, replay) = HUB.lock().unwrap().subscribe(id, last_event_id)?;
+    let subscription = Subscription {
+        id,
+        rx,
+        replay: Some(replay),
+    };
+
+    let nb_users = ws::broadcast_user_count();
+    info!(
+        subscriber = subscription.id,
+        users = nb_users,
+        resumed = last_event_id.is_some(),
+        "Event stream opened"
+    );
+    Some(subscription)
+}
+
+// body of an event stream, it ends when the subscriber is dropped from the hub
+pub struct EventBody {
+    subscription: Subscription,
+    keep_alive: Interval,
+    _in_flight: InFlight, // the request lasts as long as its stream
+}
+
+impl EventBody {
+    pub fn new(subscription: Subscription, in_flight: InFlight) -> Self {
+        let period = Duration::from_secs(constants::EVENTS_KEEP_ALIVE);
+        EventBody {
+            subscription,
+            keep_alive: time::interval_at(Instant::now() + period, period),
+            _in_flight: in_flight,
+        }
+    }
+}
+
+impl Body for EventBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        let this = self.get_mut();
+        if let Some(replay) = this.subscription.replay.take() {
+            return Poll::Ready(Some(Ok(Frame::data(replay))));
+        }
+
+        match this.subscription.rx.poll_recv(cx) {
+            Poll::Ready(Some(event)) => {
+                this.keep_alive.reset();
+                return Poll::Ready(Some(Ok(Frame::data(event))));
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {}
+        }
+
+        // only when nothing was sent for a whole period
+        this.keep_alive
+            .poll_tick(cx)
+            .map(|_| Some(Ok(Frame::data(Bytes::from_static(KEEP_ALIVE)))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the events of a replay, without the retry delay
+    fn replayed(replay: &Bytes) -> String {
+        let text = String::from_utf8_lossy(replay).into_owned();
+        let retry = format!("retry: {}\n\n", constants::EVENTS_RETRY);
+        text.strip_prefix(&retry).unwrap().to_string()
+    }
+
+    #[test]
+    fn event_ids_of_this_process() {
+        assert_eq!(parse_event_id(&event_id(7)), Some(7));
+        assert_eq!(parse_event_id(&format!(" {} ", event_id(12))), Some(12));
+        assert_eq!(parse_event_id(&format!("{:x}-3", *EPOCH - 1)), None);
+        assert_eq!(parse_event_id(&format!("{:x}-3", *EPOCH + 1)), None);
+        for garbage in ["", "-", "7", "zz-3", "-3", &format!("{:x}-", *EPOCH)] {
+            assert_eq!(parse_event_id(garbage), None, "{garbage:?}");
+        }
+        assert_eq!(parse_event_id(&format!("{:x}-x", *EPOCH)), None);
+        assert_eq!(parse_event_id(&format!("{:x}--3", *EPOCH)), None);
+    }
+
+    #[test]
+    fn replays_the_messages_after_last_event_id() {
+        let mut hub = Hub::new();
+        for n in 1..=3 {
+            hub.publish(&format!("{{\"n\":{n}}}"), true);
+        }
+        hub.publish("{\"users\":4}", false); // not kept
+
+        let (_, replay) = hub.subscribe(1, Some(&event_id(1))).unwrap();
+        assert_eq!(
+            replayed(&replay),
+            format!(
+                "id: {}\ndata: {{\"n\":2}}\n\nid: {}\ndata: {{\"n\":3}}\n\n",
+                event_id(2),
+                event_id(3)
+            )
+        );
+
+        // nothing to resume from
+        let (_, replay) = hub.subscribe(2, None).unwrap();
+        assert_eq!(replayed(&replay), "");
+    }
+
+    #[test]
+    fn does_not_resume_other_ids() {
+        let mut hub = Hub::new();
+        hub.publish("{\"n\":1}", true);
+        let foreign = format!("{:x}-0", *EPOCH + 1);
+        let (_, replay) = hub.subscribe(1, Some(&foreign)).unwrap();
+        assert_eq!(replayed(&replay), "");
+        let (_, replay) = hub.subscribe(2, Some("garbage")).unwrap();
+        assert_eq!(replayed(&replay), "");
+    }
+
+    #[test]
+    fn backlog_keeps_the_latest() {
+        let mut hub = Hub::new();
+        for n in 0..constants::EVENTS_BACKLOG + 5 {
+            hub.publish(&format!("{n}"), true);
+        }
+        assert_eq!(hub.backlog.len(), constants::EVENTS_BACKLOG);
+        assert_eq!(hub.backlog.front().unwrap().0, 6);
+    }
+
+    #[test]
+    fn drops_a_subscriber_that_falls_behind() {
+        let mut hub = Hub::new();
+        let (mut slow, _) = hub.subscribe(1, None).unwrap();
+        let (mut fast, _) = hub.subscribe(2, None).unwrap();
+
+        for n in 0..constants::EVENTS_BUFF_EVENTS {
+            hub.publish(&format!("{n}"), true);
+            assert!(fast.try_recv().is_ok());
+        }
+        assert_eq!(hub.subscribers.len(), 2);
+
+        // the channel of the slow one is full
+        hub.publish("full", true);
+        assert_eq!(hub.subscribers.len(), 1);
+        assert!(hub.subscribers.contains_key(&2));
+        assert!(fast.try_recv().is_ok());
+
+        // what it received is still read, then its stream ends
+        for _ in 0..constants::EVENTS_BUFF_EVENTS {
+            assert!(slow.try_recv().is_ok());
+        }
+        assert_eq!(
+            slow.try_recv(),
+            Err(tokio::sync::mpsc::error::TryRecvError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn refuses_subscribers_over_the_limit() {
+        let mut hub = Hub::new();
+        for id in 0..constants::EVENTS_MAX_SUBSCRIBERS {
+            assert!(hub.subscribe(id, None).is_some());
+        }
+        assert!(hub.subscribe(usize::MAX, None).is_none());
+    }
+}
@@ -0,0 +1,121 @@
+/*  Atom feed of the guestbook
+    - The FEED_ENTRIES most recent messages at FEED_PATH, newest first, for following the wall
+      without keeping a tab open
+    - An entry is identified by the id and the time of its message (the id of a deleted
+      latest message comes back after a restart) and dated by when it was posted, the feed
+      itself by the last change to the messages
+    - Built once per version of the messages, db::add_message and db::delete_message drop it
+*/
+use crate::config;
+use crate::constants;
+use crate::content;
+use crate::db;
+use crate::xml;
+
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Feed {
+    pub etag: String,
+    pub modified: SystemTime,
+    pub body: Bytes,
+}
+
+static CACHE: Lazy<Mutex<Option<Arc<Feed>>>> = Lazy::new(|| Mutex::new(None));
+
+// the messages changed, the next request builds the feed again
+pub fn invalidate() {
+    *CACHE.lock().unwrap() = None;
+}
+
+pub async fn get() -> Arc<Feed> {
+    if let Some(feed) = CACHE.lock().unwrap().clone() {
+        return feed;
+    }
+
+    let version = db::version();
+    let modified = db::changed_at();
+    let messages = db::recent_messages(constants::FEED_ENTRIES).await;
+    let feed = Arc::new(Feed {
+        etag: format!("\"{version}\""),
+        modified,
+        body: Bytes::from(render(&messages, modified)),
+    });
+
+    // a message added meanwhile already invalidated the cache, this feed is stale, under the
+    // lock an invalidation either happened before the check or clears it after
+    let mut cache = CACHE.lock().unwrap();
+    if db::version() == version {
+        *cache = Some(feed.clone());
+    }
+    feed
+}
+
+// 2025-06-13T12:00:00Z
+fn rfc3339(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    format!("{}T{h:02}:{m:02}:{s:02}Z", content::date_of(secs))
+}
+
+// the start of a message, on one line
+fn title(msg: &str) -> String {
+    let line = msg.split_whitespace().collect::<Vec<_>>().join(" ");
+    match line.char_indices().nth(constants::FEED_TITLE_LENGTH) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line,
+    }
+}
+
+fn render(messages: &[db::Message], modified: SystemTime) -> String {
+    let site = &config::get().site;
+    let base = xml::escape(site.url.trim_end_matches('/'));
+    let updated = rfc3339(
+        modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    );
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    let _ = write!(
+        out,
+        concat!(
+            "<feed xmlns=\"http://www.w3.org/2005/Atom\">\n",
+            "  <title>{title} - Guestbook</title>\n",
+            "  <link href=\"{base}{path}\" rel=\"self\" type=\"application/atom+xml\"/>\n",
+            "  <link href=\"{base}/\" rel=\"alternate\" type=\"text/html\"/>\n",
+            "  <id>{base}{path}</id>\n",
+            "  <updated>{updated}</updated>\n",
+            "  <author><name>Guestbook visitors</name></author>\n",
+        ),
+        title = xml::escape(&site.title),
+        base = base,
+        path = constants::FEED_PATH,
+        updated = updated,
+    );
+    for msg in messages {
+        let _ = write!(
+            out,
+            concat!(
+                "  <entry>\n",
+                "    <title>{title}</title>\n",
+                "    <link href=\"{base}/\" rel=\"alternate\" type=\"text/html\"/>\n",
+                "    <id>{base}{path}#{id}-{at}</id>\n",
+                "    <updated>{updated}</updated>\n",
+                "    <content type=\"text\">{content}</content>\n",
+                "  </entry>\n",
+            ),
+            title = xml::escape(&title(&msg.text)),
+            base = base,
+            path = constants::FEED_PATH,
+            id = msg.id,
+            at = msg.at,
+            updated = rfc3339(msg.at),
+            content = xml::escape(&msg.text),
+        );
+    }
+    out.push_str("</feed>\n");
+    out
+}
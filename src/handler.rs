@@ -1,15 +1,36 @@
+use crate::activity::InFlight;
+use crate::admin;
+use crate::assets::{self, Layout};
+use crate::compress::Encoding;
+use crate::conditional;
+use crate::config;
 use crate::constants;
-use crate::crypt;
+use crate::csp;
 use crate::db;
+use crate::events::{self, EventBody, EventStream};
+use crate::feed;
+use crate::health;
+use crate::metrics;
+use crate::middleware::{self, AccessLog, BodyLimit, ClientIpLayer, Compression, ErrorPages};
+use crate::middleware::{Head, RateLimit, RequestIdLayer, SecurityHeaders};
+use crate::posts;
+use crate::router::{HandlerResult, Params, Router};
+use crate::sitemap;
 use crate::ws;
 
 use bytes::Bytes;
-use http_body_util::Full;
+use http_body_util::{Either, Full, Limited};
+use hyper::body::Incoming;
+use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, ETAG, LAST_MODIFIED, LOCATION, VARY};
 use hyper::{Method, StatusCode};
 use hyper::{Request, Response};
-use std::net::IpAddr;
+use hyper_tungstenite::{HyperWebsocketStream, tungstenite};
+use once_cell::sync::OnceCell;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::OwnedSemaphorePermit;
 
-use tracing::{error, info, warn};
+use tracing::{Instrument, error, field, info_span};
 
 macro_rules! empty {
     () => {
@@ -25,7 +46,7 @@ macro_rules! full {
 
 macro_rules! err {
     ($status:expr, $log:expr) => {{
-        error!("{}", $log);
+        error!(status = $status.as_u16(), "{}", $log);
         let mut res = Response::new(empty!());
         *res.status_mut() = $status;
         Ok(res)
@@ -46,150 +67,514 @@ macro_rules! dump_headers {
     }};
 }
 
-pub async fn handle_request(
-    mut req: Request<hyper::body::Incoming>,
-) -> Result<Response<Full<Bytes>>, hyper::Error> {
-    let headers: &hyper::HeaderMap = req.headers();
+// routes and middlewares of the main listener
+fn build_router() -> Result<Router, String> {
+    let cfg = config::get();
+    let mut router = Router::new()
+        .route(Method::GET, "/", index)
+        .route(Method::GET, "/ws", websocket)
+        .route(Method::GET, constants::EVENTS_PATH, event_stream)
+        .route(Method::GET, constants::FEED_PATH, messages_feed)
+        .route(Method::CONNECT, "/ws", websocket_h2)
+        // Liveness and readiness probes
+        .route(Method::GET, "/healthz", healthz)
+        .route(Method::GET, "/readyz", readyz)
+        // Authenticated admin API, every method is handled there
+        .any("/admin/*", admin::handle_admin)
+        // Static files, embedded in the binary or read from assets/ in dev mode,
+        // crawlers look for these two at their own path
+        .route(Method::GET, "/robots.txt", static_file)
+        .route(Method::GET, "/sitemap.xml", sitemap)
+        .matching(Method::GET, "static", is_static_file, static_file)
+        // Posts, their listings and feeds
+        .route(Method::GET, constants::POSTS_PATH, posts_index)
+        .route(Method::GET, "/tags/{tag}", posts_tagged)
+        .route(Method::GET, constants::POSTS_ATOM_PATH, posts_atom)
+        .route(Method::GET, constants::POSTS_RSS_PATH, posts_rss)
+        // Markdown pages of content/, at clean paths
+        .matching(Method::GET, "page", is_page, page);
+    if cfg.csp_report.enabled {
+        router = router.route(Method::POST, constants::CSP_REPORT_PATH, csp::handle_report);
+    }
+    if cfg.metrics.enabled && cfg.metrics.listen.is_none() {
+        router = router.route(Method::GET, "/metrics", metrics);
+    }
 
-    let cf_ip_opt = headers
-        .get("CF-Connecting-IP")
-        .and_then(|v| v.to_str().ok());
+    router = router
+        .layer(RequestIdLayer)
+        .layer(ClientIpLayer)
+        .layer(AccessLog)
+        .layer(SecurityHeaders::new(&cfg.security, cfg.csp_report.enabled)?)
+        .layer(Head)
+        .layer(ErrorPages);
+    if cfg.rate_limit.enabled {
+        router = router.layer(RateLimit::new(
+            cfg.rate_limit.per_second,
+            cfg.rate_limit.burst,
+        ));
+    }
+    Ok(router.layer(BodyLimit).layer(Compression))
+}
+
+static ROUTER: OnceCell<Router> = OnceCell::new();
 
-    let cf_ip: IpAddr = if let Some(ip_str) = cf_ip_opt {
-        match ip_str.parse() {
-            Ok(ip) => ip,
-            Err(_) => {
-                // Invalid IP format in header
-                return err!(
-                    StatusCode::FORBIDDEN,
-                    format!(
-                        "Invalid CF-Connecting-IP header: '{}' |x| {}",
-                        ip_str,
-                        dump_headers!(headers)
-                    )
-                );
+// builds the router once the configuration is loaded, a bad security header fails here
+pub fn initialize() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ROUTER
+        .set(build_router()?)
+        .map_err(|_| "router already initialized")?;
+    Ok(())
+}
+
+#[inline(always)]
+fn router() -> &'static Router {
+    ROUTER.get_or_init(|| build_router().expect("invalid security headers"))
+}
+
+async fn metrics_response() -> Response<Full<Bytes>> {
+    let body = metrics::render(
+        ws::get_user_count(),
+        events::subscriber_count(),
+        db::message_count().await,
+    );
+    Response::builder()
+        .header("Cache-Control", "no-store")
+        .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+        .body(full!(body))
+        .unwrap()
+}
+
+// service of the separate metrics listener, only serves GET /metrics
+pub async fn handle_metrics(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Ok(metrics_response().await),
+        _ => {
+            let mut res = Response::new(empty!());
+            *res.status_mut() = StatusCode::NOT_FOUND;
+            Ok(res)
+        }
+    }
+}
+
+// runs the WebSocket session once the upgrade completes, in its own task
+fn spawn_websocket<W>(websocket: W, req: &Request<RequestBody>)
+where
+    W: Future<Output = Result<HyperWebsocketStream, tungstenite::Error>> + Send + 'static,
+{
+    let permit = conn_permit(req);
+    let ip = middleware::client_ip(req);
+    // the span outlives the request, every line of the session carries the id of its
+    // upgrade request, user is recorded once the id is assigned
+    let ws_span = info_span!(
+        parent: None,
+        "ws",
+        id = %middleware::request_id(req),
+        ip = %ip,
+        user = field::Empty,
+    );
+    tokio::spawn(
+        async move {
+            if let Err(e) = ws::handle_websocket(websocket, ip).await {
+                error!(error = %e, "WebSocket error");
             }
+            drop(permit);
+        }
+        .instrument(ws_span),
+    );
+}
+
+// what the listener knows about the connection a request came from, an extension of
+// every request
+#[derive(Clone, Debug)]
+pub struct ConnInfo {
+    pub peer: SocketAddr, // TCP peer, or the client address of a PROXY header
+    pub tls: bool,
+    pub permit: Arc<OwnedSemaphorePermit>, // max_connections slot, kept by upgraded WebSockets
+}
+
+// request bodies stop being read at limits.max_body_size
+pub type RequestBody = Limited<Incoming>;
+
+// responses of the router, or the event stream of /events
+pub type ResponseBody = Either<Full<Bytes>, EventBody>;
+
+// `in_flight` ends with the request, or with the event stream it answers with
+pub async fn handle_request(
+    req: Request<Incoming>,
+    conn: ConnInfo,
+    in_flight: InFlight,
+) -> Result<Response<ResponseBody>, hyper::Error> {
+    // every log line of the request carries these fields, id and ip are recorded by the
+    // middlewares
+    let span = info_span!(
+        "request",
+        id = field::Empty,
+        ip = field::Empty,
+        method = %req.method(),
+        path = %req.uri().path(),
+    );
+
+    let is_get = req.method() == Method::GET;
+    let mut req = req.map(|body| Limited::new(body, config::get().limits.max_body_size));
+    req.extensions_mut().insert(conn);
+    let res = router().handle(req).instrument(span).await?;
+
+    // the subscription of a HEAD request is dropped with the response
+    let stream = res.extensions().get::<EventStream>().and_then(|s| s.take());
+    Ok(match stream {
+        Some(subscription) if is_get => {
+            res.map(|_| Either::Right(EventBody::new(subscription, in_flight)))
         }
+        _ => res.map(Either::Left),
+    })
+}
+
+async fn index(req: Request<RequestBody>) -> HandlerResult {
+    // kept by the browser but revalidated on every load
+    let etag = db::index_etag();
+    let mut response_builder = Response::builder()
+        .header("Cache-Control", "no-cache")
+        .header(ETAG, &etag);
+    if config::get().compression.enabled {
+        response_builder = response_builder.header(VARY, "Accept-Encoding");
+    }
+    if conditional::not_modified(req.headers(), &etag, None) {
+        return Ok(response_builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(empty!())
+            .unwrap());
+    }
+
+    // the CSP of SecurityHeaders allows the script tags with this nonce
+    let nonce = middleware::nonce(&req);
+    let nb_users = ws::get_viewer_count() + 1;
+
+    match db::render(&nb_users, &nonce).await {
+        Ok(body) => Ok(response_builder
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(full!(body))
+            .unwrap()),
+        Err(e) => {
+            err!(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Internal Server Error |x| {e}")
+            )
+        }
+    }
+}
+
+// a cached feed, or 304 if the client has it
+fn feed_response(
+    req: &Request<RequestBody>,
+    feed: &feed::Feed,
+    cache_control: &'static str,
+    content_type: &'static str,
+) -> HandlerResult {
+    let response_builder = Response::builder()
+        .header("Cache-Control", cache_control)
+        .header(ETAG, &feed.etag)
+        .header(LAST_MODIFIED, conditional::http_date(feed.modified));
+    if conditional::not_modified(req.headers(), &feed.etag, Some(feed.modified)) {
+        return Ok(response_builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(empty!())
+            .unwrap());
+    }
+    Ok(response_builder
+        .header("Content-Type", content_type)
+        .body(Full::new(feed.body.clone()))
+        .unwrap())
+}
+
+// Atom feed of the guestbook, the same until a message is added or deleted
+async fn messages_feed(req: Request<RequestBody>) -> HandlerResult {
+    let feed = feed::get().await;
+    feed_response(
+        &req,
+        &feed,
+        "no-cache",
+        "application/atom+xml; charset=utf-8",
+    )
+}
+
+// read-only live updates for the clients that cannot open a WebSocket
+async fn event_stream(req: Request<RequestBody>) -> HandlerResult {
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok());
+    let Some(subscription) = events::subscribe(last_event_id) else {
+        return err!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "Service Unavailable: Maximum number of event streams reached: {}",
+                constants::EVENTS_MAX_SUBSCRIBERS
+            )
+        );
+    };
+    Ok(Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-store")
+        .header("X-Accel-Buffering", "no") // streamed as is by nginx
+        .extension(EventStream::from(subscription))
+        .body(empty!())
+        .unwrap())
+}
+
+async fn websocket(mut req: Request<RequestBody>) -> HandlerResult {
+    if hyper_tungstenite::is_upgrade_request(&req) {
+        let (response, websocket) = hyper_tungstenite::upgrade(&mut req, None).unwrap();
+        spawn_websocket(websocket, &req);
+        Ok(response)
     } else {
-        let ip = headers
-            .get("X-Forwarded-For")
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("")
-            .parse()
-            .unwrap_or(IpAddr::from([127, 0, 0, 1]));
-        warn!(
-            "[-------->] No CF-Connecting-IP header found, using X-Forwarded-For or defaulting to: {}",
-            ip
+        err!(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Bad Request: Not a WebSocket upgrade request |x| {}",
+                dump_headers!(req.headers())
+            )
+        )
+    }
+}
+
+// WebSocket over HTTP/2 (RFC 8441), the stream is the connection once answered with 200
+async fn websocket_h2(mut req: Request<RequestBody>) -> HandlerResult {
+    if !ws::is_extended_connect(&req) {
+        return err!(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Bad Request: Not an extended CONNECT |x| {}",
+                dump_headers!(req.headers())
+            )
         );
-        ip
+    }
+    if req
+        .headers()
+        .get("Sec-WebSocket-Version")
+        .map(|v| v.as_bytes())
+        != Some(b"13")
+    {
+        return err!(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Bad Request: Unsupported WebSocket version |x| {}",
+                dump_headers!(req.headers())
+            )
+        );
+    }
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    spawn_websocket(ws::upgrade_extended_connect(on_upgrade), &req);
+    Ok(Response::new(empty!()))
+}
+
+// the max_connections slot of the connection, an upgraded WebSocket keeps it
+fn conn_permit(req: &Request<RequestBody>) -> Arc<OwnedSemaphorePermit> {
+    req.extensions()
+        .get::<ConnInfo>()
+        .expect("ConnInfo is inserted by handle_request")
+        .permit
+        .clone()
+}
+
+async fn healthz(_req: Request<RequestBody>) -> HandlerResult {
+    Ok(Response::builder()
+        .header("Cache-Control", "no-store")
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(full!("ok"))
+        .unwrap())
+}
+
+async fn readyz(_req: Request<RequestBody>) -> HandlerResult {
+    let readiness = health::readiness();
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
     };
+    Ok(Response::builder()
+        .status(status)
+        .header("Cache-Control", "no-store")
+        .header("Content-Type", "application/json")
+        .body(full!(serde_json::to_vec(&readiness).unwrap_or_default()))
+        .unwrap())
+}
+
+async fn metrics(_req: Request<RequestBody>) -> HandlerResult {
+    Ok(metrics_response().await)
+}
+
+// a page, or a page with a trailing slash that redirects to it
+fn is_page(path: &str) -> bool {
+    let path = path.strip_suffix('/').unwrap_or(path);
+    assets::get().page(path).is_some()
+}
 
-    let method = req.method();
+async fn page(req: Request<RequestBody>) -> HandlerResult {
     let path = req.uri().path();
-    let ua = headers
-        .get("User-Agent")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("Unknown User-Agent");
+    let Some(page) = assets::get().page(path.strip_suffix('/').unwrap_or(path)) else {
+        let mut res = Response::new(empty!());
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(res);
+    };
+    // one url per page
+    if path != page.path {
+        return Ok(Response::builder()
+            .status(StatusCode::PERMANENT_REDIRECT)
+            .header(LOCATION, &page.path)
+            .body(empty!())
+            .unwrap());
+    }
 
-    // log the request: ip, method, path, UA
-    info!("[{}] {} {} {}", cf_ip, method, path, ua);
+    // weak, the nonce differs from one response to the next
+    let etag = format!("W/\"{}\"", &page.hash[..page.hash.len().min(16)]);
+    let mut response_builder = Response::builder()
+        .header("Cache-Control", "no-cache")
+        .header(ETAG, &etag);
+    if config::get().compression.enabled {
+        response_builder = response_builder.header(VARY, "Accept-Encoding");
+    }
+    if conditional::not_modified(req.headers(), &etag, None) {
+        return Ok(response_builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(empty!())
+            .unwrap());
+    }
 
-    let response_builder = Response::builder()
-        // TODO, why does it not work ? .header("Content-Security-Policy", "default-src 'none'; img-src 'self'")
-        .header("X-Permitted-Cross-Domain-Policies", "none")
-        .header("X-Content-Type-Options", "nosniff")
-        .header("X-Frame-Options", "DENY")
-        .header("Referrer-Policy", "no-referrer")
-        .header(
-            "Permissions-Policy",
-            "geolocation=(), microphone=(), camera=()",
-        )
-        .header("Cross-Origin-Resource-Policy", "same-origin")
-        .header("Cross-Origin-Opener-Policy", "same-origin")
-        .header("Cross-Origin-Embedder-Policy", "require-corp");
-
-    match (method, path) {
-        (&Method::GET, "/") => {
-            let nonce = crypt::generate_nonce_base64(32);
-            let nb_users = ws::get_user_count() + 1;
-
-            match db::render(&nb_users,&nonce).await {
-                Ok(body) => Ok(response_builder
-                    .header("Content-Security-Policy", format!(
-                        "default-src 'none'; script-src 'nonce-{nonce}'; style-src 'self'; img-src 'self'; connect-src 'self'"
-                    ))
-                    .header("Cache-Control", "no-cache, no-store, must-revalidate")
-                    .header("Content-Type", "text/html; charset=utf-8")
-                    .body(full!(body)).unwrap()),
-                Err(e) => {
-                    err!(StatusCode::INTERNAL_SERVER_ERROR, format!("[{cf_ip}] Internal Server Error |x| {e}"))
-                }
-            }
-        }
+    let meta = if posts::is_post(&page) {
+        posts::meta(&page)
+    } else {
+        String::new()
+    };
+    let layout = Layout {
+        meta: &meta,
+        ..Layout::from(page.as_ref())
+    };
+    render_layout(&req, response_builder, &layout)
+}
 
-        (&Method::GET, "/ws") => {
-            if hyper_tungstenite::is_upgrade_request(&req) {
-                let (response, websocket) = hyper_tungstenite::upgrade(&mut req, None).unwrap();
-                tokio::spawn(async move {
-                    if let Err(e) = ws::handle_websocket(websocket, cf_ip).await {
-                        error!("[{}] WebSocket error: {}", cf_ip, e);
-                    }
-                });
-                Ok(response)
-            } else {
-                err!(
-                    StatusCode::BAD_REQUEST,
-                    format!(
-                        "[{}] Bad Request: Not a WebSocket upgrade request |x| {}",
-                        cf_ip,
-                        dump_headers!(headers)
-                    )
-                )
-            }
+// `layout` as the body of `response_builder`
+fn render_layout(
+    req: &Request<RequestBody>,
+    response_builder: hyper::http::response::Builder,
+    layout: &Layout,
+) -> HandlerResult {
+    match assets::get().render_layout(layout, &middleware::nonce(req)) {
+        Ok(body) => Ok(response_builder
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(full!(body))
+            .unwrap()),
+        Err(e) => {
+            err!(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Internal Server Error |x| {e}")
+            )
         }
+    }
+}
 
-        // From here on, static files that have fixed paths
-        (&Method::GET, "/sitemap.xml") => Ok(response_builder
-            .header("Cache-Control", "public, max-age=86400")
-            .header("Content-Type", "application/xml; charset=utf-8")
-            .body(full!(constants::F_SITEMAP))
-            .unwrap()),
+async fn posts_index(req: Request<RequestBody>) -> HandlerResult {
+    let posts = posts::all(assets::get().pages());
+    let description = format!("Posts of {}", config::get().site.title);
+    let layout = Layout {
+        title: "Posts",
+        description: &description,
+        meta: "",
+        content: &posts::listing(&posts, &posts),
+    };
+    render_layout(
+        &req,
+        Response::builder().header("Cache-Control", "no-cache"),
+        &layout,
+    )
+}
 
-        (&Method::GET, "/robots.txt") => Ok(response_builder
-            .header("Cache-Control", "public, max-age=86400")
-            .header("Content-Type", "text/plain; charset=utf-8")
-            .body(full!(constants::F_ROBOTS))
-            .unwrap()),
+async fn posts_tagged(req: Request<RequestBody>) -> HandlerResult {
+    let tag = req
+        .extensions()
+        .get::<Params>()
+        .and_then(|params| params.get("tag"))
+        .unwrap_or("");
+    let all = posts::all(assets::get().pages());
+    let tagged = posts::tagged(&all, tag);
+    if tagged.is_empty() {
+        let mut res = Response::new(empty!());
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(res);
+    }
 
-        // From here on, static files that need cache busting for modifications
-        (&Method::GET, constants::URL_ICON) => Ok(response_builder
-            .header("Cache-Control", "public, max-age=31536000, immutable")
-            .header("Content-Type", "image/png")
-            .body(full!(constants::F_ICON))
-            .unwrap()),
+    let title = format!("Posts tagged \"{tag}\"");
+    let layout = Layout {
+        title: &title,
+        description: &title,
+        meta: "",
+        content: &posts::listing(&tagged, &all),
+    };
+    render_layout(
+        &req,
+        Response::builder().header("Cache-Control", "no-cache"),
+        &layout,
+    )
+}
 
-        (&Method::GET, constants::URL_CSS) => Ok(response_builder
-            .header("Cache-Control", "public, max-age=31536000, immutable")
-            .header("Content-Type", "text/css; charset=utf-8")
-            .body(full!(constants::F_CSS))
-            .unwrap()),
+async fn posts_atom(req: Request<RequestBody>) -> HandlerResult {
+    feed_response(
+        &req,
+        &posts::feeds().atom,
+        "public, max-age=3600",
+        "application/atom+xml; charset=utf-8",
+    )
+}
 
-        (&Method::GET, constants::URL_JS) => Ok(response_builder
-            .header("Cache-Control", "public, max-age=31536000, immutable")
-            .header("Content-Type", "application/javascript; charset=utf-8")
-            .body(full!(constants::F_JS))
-            .unwrap()),
+async fn posts_rss(req: Request<RequestBody>) -> HandlerResult {
+    feed_response(
+        &req,
+        &posts::feeds().rss,
+        "public, max-age=3600",
+        "application/rss+xml; charset=utf-8",
+    )
+}
 
-        (&Method::GET, constants::URL_BG) => Ok(response_builder
-            .header("Cache-Control", "public, max-age=31536000, immutable")
-            .header("Content-Type", "image/webp")
-            .body(full!(constants::F_BG))
-            .unwrap()),
+async fn sitemap(_req: Request<RequestBody>) -> HandlerResult {
+    Ok(Response::builder()
+        .header("Cache-Control", "public, max-age=86400")
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(full!(sitemap::render(&assets::get().pages())))
+        .unwrap())
+}
 
-        // Return 404 Not Found for other routes.
-        _ => err!(
-            StatusCode::NOT_FOUND,
-            format!("[{}] 404 Not Found |x| {} {}", cf_ip, method, path)
-        ),
+fn is_static_file(path: &str) -> bool {
+    assets::get().get(path).is_some()
+}
+
+async fn static_file(req: Request<RequestBody>) -> HandlerResult {
+    let Some(asset) = assets::get().get(req.uri().path()) else {
+        let mut res = Response::new(empty!());
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(res);
+    };
+
+    let headers = req.headers();
+    let (encoding, body) = asset.negotiate(headers.get(ACCEPT_ENCODING));
+    let etag = asset.etag(encoding);
+    let mut response_builder = Response::builder();
+    if asset.has_variants() {
+        response_builder = response_builder.header(VARY, "Accept-Encoding");
+    }
+    response_builder = response_builder
+        .header("Cache-Control", asset.cache_control)
+        .header(ETAG, &etag)
+        .header(LAST_MODIFIED, conditional::http_date(asset.modified));
+    if conditional::not_modified(headers, &etag, Some(asset.modified)) {
+        return Ok(response_builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(empty!())
+            .unwrap());
+    }
+    if encoding != Encoding::Identity {
+        response_builder = response_builder.header(CONTENT_ENCODING, encoding.as_str());
     }
+    Ok(response_builder
+        .header("Content-Type", asset.content_type)
+        .body(full!(body))
+        .unwrap())
 }
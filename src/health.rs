@@ -0,0 +1,81 @@
+use crate::constants;
+use crate::db;
+use crate::ws;
+
+use serde::Serialize;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub db_loaded: bool,
+    pub flush_healthy: bool,
+    pub users: usize,
+    pub max_users: usize,
+}
+
+pub fn readiness() -> Readiness {
+    let db_loaded = db::is_loaded();
+    let flush_healthy = db::is_flush_healthy();
+    let users = ws::get_user_count();
+
+    Readiness {
+        ready: db_loaded && flush_healthy && users < constants::WS_MAX_USERS,
+        db_loaded,
+        flush_healthy,
+        users,
+        max_users: constants::WS_MAX_USERS,
+    }
+}
+
+// sends a bare HTTP/1.1 GET and returns the status code of the response
+async fn probe(addr: SocketAddr, path: &str) -> Result<u16, std::io::Error> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // the status line fits in the first read: "HTTP/1.1 200 OK"
+    let mut buf = [0u8; 64];
+    let n = stream.read(&mut buf).await?;
+    std::str::from_utf8(&buf[..n])
+        .ok()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| std::io::Error::other("malformed status line"))
+}
+
+// `webrs healthcheck`: probes /healthz of the local server, returns the process exit code
+// so it can be used as a container health check. /readyz is left to load balancers, a full
+// chat room is not ready for more users but does not need a restart
+pub async fn run_healthcheck() -> i32 {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, constants::MAIN_HOST.1));
+    let path = "/healthz";
+
+    let result = timeout(
+        Duration::from_secs(constants::HEALTH_PROBE_TIMEOUT),
+        probe(addr, path),
+    )
+    .await;
+
+    match result {
+        Ok(Ok(200)) => {
+            println!("{path}: ok");
+            0
+        }
+        Ok(Ok(status)) => {
+            eprintln!("{path}: unhealthy, status {status}");
+            1
+        }
+        Ok(Err(e)) => {
+            eprintln!("{path}: probe failed: {e}");
+            1
+        }
+        Err(_) => {
+            eprintln!("{path}: probe timed out");
+            1
+        }
+    }
+}
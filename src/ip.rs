@@ -0,0 +1,366 @@
+/*  Client IP resolution
+    - Forwarding headers are only read when the TCP peer is a trusted proxy, otherwise
+      the peer address is the client, so clients cannot spoof their address
+    - Lists of hops (X-Forwarded-For, Forwarded) are read right to left and the first
+      address that is not a trusted proxy is the client
+*/
+use crate::config::{self, ProxyConfig, ProxyMode};
+
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+// network in CIDR notation, e.g. "10.0.0.0/8" or "fc00::/7", a bare address is a /32 or /128
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid network address: '{s}'"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length: '{s}'"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+pub fn is_trusted(ip: IpAddr) -> bool {
+    trusted_by(&config::get().proxy.trusted, ip)
+}
+
+fn trusted_by(trusted: &[Cidr], ip: IpAddr) -> bool {
+    trusted.iter().any(|cidr| cidr.contains(ip))
+}
+
+// walks a list of hops (client first, closest proxy last) from the right,
+// skipping trusted proxies, an unparsable hop stops the walk
+fn rightmost_untrusted<'a>(
+    hops: impl DoubleEndedIterator<Item = &'a str>,
+    peer: IpAddr,
+    trusted: &[Cidr],
+) -> IpAddr {
+    let mut client = peer;
+    for hop in hops.rev() {
+        match parse_hop(hop) {
+            Some(ip) => {
+                client = ip;
+                if !trusted_by(trusted, ip) {
+                    break;
+                }
+            }
+            None => {
+                warn!(
+                    hop,
+                    "Unparsable forwarding hop, using the last valid address"
+                );
+                break;
+            }
+        }
+    }
+    client
+}
+
+// parses "1.2.3.4", "1.2.3.4:80", "2001:db8::1" or "[2001:db8::1]:80", quoted or not
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(ip) = hop.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    hop.rsplit_once(':')?.0.parse().ok()
+}
+
+// values of the "for" parameters of every RFC 7239 Forwarded header, in order
+fn forwarded_for(headers: &HeaderMap) -> Vec<&str> {
+    headers
+        .get_all("Forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then_some(value.trim())
+            })
+        })
+        .collect()
+}
+
+// returns the address of the client that sent the request
+pub fn client_ip(headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+    resolve(headers, peer, &config::get().proxy)
+}
+
+fn resolve(headers: &HeaderMap, peer: IpAddr, proxy: &ProxyConfig) -> IpAddr {
+    let peer = peer.to_canonical();
+    let mode = proxy.mode;
+
+    // with the PROXY protocol the listener already replaced the peer by the client
+    if matches!(mode, ProxyMode::None | ProxyMode::ProxyProtocol) {
+        return peer;
+    }
+    if !trusted_by(&proxy.trusted, peer) {
+        debug!(%peer, "Untrusted peer, ignoring forwarding headers");
+        return peer;
+    }
+
+    match mode {
+        ProxyMode::Cloudflare => {
+            let header = headers
+                .get("CF-Connecting-IP")
+                .and_then(|v| v.to_str().ok());
+            match header.map(|v| v.trim().parse::<IpAddr>()) {
+                Some(Ok(ip)) => ip,
+                Some(Err(_)) => {
+                    warn!(
+                        header,
+                        "Invalid CF-Connecting-IP header, using the peer address"
+                    );
+                    peer
+                }
+                None => {
+                    warn!(
+                        "No CF-Connecting-IP header from a trusted proxy, using the peer address"
+                    );
+                    peer
+                }
+            }
+        }
+
+        ProxyMode::XForwardedFor => {
+            let hops: Vec<&str> = headers
+                .get_all("X-Forwarded-For")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .collect();
+            if hops.is_empty() {
+                warn!("No X-Forwarded-For header from a trusted proxy, using the peer address");
+            }
+            rightmost_untrusted(hops.into_iter(), peer, &proxy.trusted)
+        }
+
+        ProxyMode::Forwarded => {
+            let hops = forwarded_for(headers);
+            if hops.is_empty() {
+                warn!("No Forwarded header from a trusted proxy, using the peer address");
+            }
+            rightmost_untrusted(hops.into_iter(), peer, &proxy.trusted)
+        }
+
+        ProxyMode::ProxyProtocol | ProxyMode::None => peer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn proxy(mode: ProxyMode) -> ProxyConfig {
+        ProxyConfig {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn ipv4_prefixes() {
+        let net = cidr("10.1.0.0/16");
+        assert!(net.contains(ip("10.1.255.7")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("192.0.2.1").contains(ip("192.0.2.1")));
+        assert!(!cidr("192.0.2.1").contains(ip("192.0.2.2")));
+        // an IPv4-mapped IPv6 peer is matched as IPv4
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("2001:db8::1")));
+        assert_eq!(cidr("192.0.2.1").to_string(), "192.0.2.1/32");
+    }
+
+    #[test]
+    fn ipv6_prefixes() {
+        let net = cidr("fc00::/7");
+        assert!(net.contains(ip("fd12:3456::1")));
+        assert!(!net.contains(ip("fe80::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("10.0.0.1")));
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+    }
+
+    #[test]
+    fn invalid_prefixes() {
+        for bad in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "example.com/24",
+            "",
+        ] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad} should not parse");
+        }
+    }
+
+    #[test]
+    fn x_forwarded_for_skips_spoofed_leftmost_entry() {
+        let proxy = proxy(ProxyMode::XForwardedFor);
+        let peer = ip("10.0.0.2");
+        // the client made up 1.1.1.1, our proxy appended the address it saw
+        let h = headers(&[("X-Forwarded-For", "1.1.1.1, 203.0.113.7")]);
+        assert_eq!(resolve(&h, peer, &proxy), ip("203.0.113.7"));
+
+        // trusted hops on the right are skipped, across several headers
+        let h = headers(&[
+            ("X-Forwarded-For", "1.1.1.1, 203.0.113.7"),
+            ("X-Forwarded-For", "10.0.0.9"),
+        ]);
+        assert_eq!(resolve(&h, peer, &proxy), ip("203.0.113.7"));
+
+        // an unparsable hop stops the walk at the last valid address
+        let h = headers(&[("X-Forwarded-For", "203.0.113.7, garbage, 10.0.0.9")]);
+        assert_eq!(resolve(&h, peer, &proxy), ip("10.0.0.9"));
+
+        // no header, the peer is the client
+        assert_eq!(resolve(&HeaderMap::new(), peer, &proxy), peer);
+    }
+
+    #[test]
+    fn forwarded_with_quoted_ipv6_and_ports() {
+        let proxy = proxy(ProxyMode::Forwarded);
+        let peer = ip("127.0.0.1");
+        let h = headers(&[(
+            "Forwarded",
+            "for=192.0.2.60;proto=http, For=\"[2001:db8:cafe::17]:4711\"",
+        )]);
+        assert_eq!(resolve(&h, peer, &proxy), ip("2001:db8:cafe::17"));
+
+        let h = headers(&[("Forwarded", "for=\"198.51.100.17:8080\";by=10.0.0.1")]);
+        assert_eq!(resolve(&h, peer, &proxy), ip("198.51.100.17"));
+
+        // a trusted last hop is skipped
+        let h = headers(&[("Forwarded", "for=198.51.100.17, for=\"[::1]\"")]);
+        assert_eq!(resolve(&h, peer, &proxy), ip("198.51.100.17"));
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let peer = ip("203.0.113.50");
+        let h = headers(&[
+            ("CF-Connecting-IP", "1.1.1.1"),
+            ("X-Forwarded-For", "1.1.1.1"),
+            ("Forwarded", "for=1.1.1.1"),
+        ]);
+        for mode in [
+            ProxyMode::Cloudflare,
+            ProxyMode::XForwardedFor,
+            ProxyMode::Forwarded,
+        ] {
+            assert_eq!(resolve(&h, peer, &proxy(mode)), peer);
+        }
+        // the same headers from a trusted peer are read
+        let trusted_peer = ip("192.168.1.1");
+        assert_eq!(
+            resolve(&h, trusted_peer, &proxy(ProxyMode::Cloudflare)),
+            ip("1.1.1.1")
+        );
+        // and never without a proxy
+        assert_eq!(
+            resolve(&h, trusted_peer, &proxy(ProxyMode::None)),
+            trusted_peer
+        );
+    }
+
+    #[test]
+    fn cloudflare_invalid_header_falls_back_to_peer() {
+        let peer = ip("10.0.0.2");
+        let h = headers(&[("CF-Connecting-IP", "not an ip")]);
+        assert_eq!(resolve(&h, peer, &proxy(ProxyMode::Cloudflare)), peer);
+    }
+}
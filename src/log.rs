@@ -1,22 +1,125 @@
+use crate::config::{self, LogFormat, LogOutput};
 use crate::constants;
+use crate::csp::CSP_TARGET;
+use crate::rotate::RotatingFile;
+use once_cell::sync::OnceCell;
 use std::fs::OpenOptions;
+use std::io::IsTerminal;
 use tracing_appender::non_blocking;
-use tracing_appender::non_blocking::WorkerGuard;
-use tracing_subscriber::fmt;
+use tracing_appender::non_blocking::{ErrorCounter, NonBlocking, NonBlockingBuilder, WorkerGuard};
+use tracing_subscriber::filter::{EnvFilter, FilterExt, filter_fn};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{Layer, Registry, fmt};
 
-pub fn init_logging() -> Result<WorkerGuard, std::io::Error> {
-    let file = OpenOptions::new()
+// events logged with this target go to AUDIT_LOG_FILE instead of the regular sinks
+pub const AUDIT_TARGET: &str = "audit";
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+// lines dropped by the lossy sinks because their worker fell behind, by sink
+static DROPPED_LINES: OnceCell<Vec<(&'static str, ErrorCounter)>> = OnceCell::new();
+
+pub fn dropped_lines() -> Vec<(&'static str, usize)> {
+    DROPPED_LINES
+        .get()
+        .map(|sinks| {
+            sinks
+                .iter()
+                .map(|(name, counter)| (*name, counter.dropped_lines()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// a writer that never blocks the caller, lines are dropped and counted once its buffer is full
+fn lossy<W: std::io::Write + Send + 'static>(writer: W) -> (NonBlocking, WorkerGuard) {
+    NonBlockingBuilder::default()
+        .buffered_lines_limit(constants::LOG_BUFFERED_LINES)
+        .finish(writer)
+}
+
+// builds one formatted output, `filter` decides which events reach it
+fn sink<F>(writer: NonBlocking, format: LogFormat, ansi: bool, filter: F) -> BoxedLayer
+where
+    F: tracing_subscriber::layer::Filter<Registry> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Human => fmt::layer()
+            .with_writer(writer)
+            .with_ansi(ansi)
+            .with_filter(filter)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_writer(writer)
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_filter(filter)
+            .boxed(),
+    }
+}
+
+pub fn init_logging() -> Result<Vec<WorkerGuard>, Box<dyn std::error::Error + Send + Sync>> {
+    let cfg = &config::get().log;
+
+    // RUST_LOG overrides the level spec of the config file
+    let spec = std::env::var("RUST_LOG").unwrap_or_else(|_| cfg.level.clone());
+    let level_filter = || -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+        let env_filter = EnvFilter::try_new(&spec)?;
+        Ok(env_filter.and(filter_fn(|meta| {
+            meta.target() != AUDIT_TARGET && meta.target() != CSP_TARGET
+        })))
+    };
+
+    let mut layers: Vec<BoxedLayer> = Vec::new();
+    let mut guards = Vec::new();
+    let mut dropped = Vec::new();
+
+    if matches!(cfg.output, LogOutput::File | LogOutput::Both) {
+        // rotation happens on the worker thread, the buffer holds the lines logged meanwhile
+        let file = RotatingFile::open(constants::LOG_FILE, cfg.rotation.clone())?;
+        let (writer, guard) = lossy(file);
+        dropped.push(("file", writer.error_counter()));
+        layers.push(sink(writer, cfg.format, false, level_filter()?));
+        guards.push(guard);
+    }
+
+    if matches!(cfg.output, LogOutput::Stdout | LogOutput::Both) {
+        let ansi = std::io::stdout().is_terminal();
+        let (writer, guard) = non_blocking(std::io::stdout());
+        layers.push(sink(writer, cfg.format, ansi, level_filter()?));
+        guards.push(guard);
+    }
+
+    // the audit log is always written, whatever the level spec says
+    let audit_file = OpenOptions::new()
         .create(true)
         .append(true)
-        .open(constants::LOG_FILE)?;
+        .open(constants::AUDIT_LOG_FILE)?;
+    let (audit_writer, audit_guard) = non_blocking(audit_file);
+    layers.push(sink(
+        audit_writer,
+        cfg.format,
+        false,
+        filter_fn(|meta| meta.target() == AUDIT_TARGET),
+    ));
+    guards.push(audit_guard);
 
-    let (non_blocking, guard) = non_blocking(file);
+    // CSP reports come from browsers, they are rotated like the main log. Browsers decide
+    // how many arrive, a burst is dropped rather than blocking the runtime
+    let csp_file = RotatingFile::open(constants::CSP_LOG_FILE, cfg.rotation.clone())?;
+    let (csp_writer, csp_guard) = lossy(csp_file);
+    dropped.push(("csp", csp_writer.error_counter()));
+    layers.push(sink(
+        csp_writer,
+        cfg.format,
+        false,
+        filter_fn(|meta| meta.target() == CSP_TARGET),
+    ));
+    guards.push(csp_guard);
 
-    fmt()
-        .with_writer(non_blocking)
-        .with_ansi(false)
-        .with_target(false)
-        .init();
+    tracing_subscriber::registry().with(layers).init();
+    let _ = DROPPED_LINES.set(dropped);
 
-    Ok(guard)
+    Ok(guards)
 }
@@ -1,67 +1,311 @@
+mod accept;
+mod activity;
+mod admin;
+mod assets;
+mod compress;
+mod conditional;
+mod config;
 mod constants;
+mod content;
 mod crypt;
+mod csp;
 mod db;
+mod errors;
+mod events;
+mod feed;
 mod handler;
+mod health;
+mod ip;
 mod log;
+mod manifest;
+mod metrics;
+mod middleware;
+mod posts;
+mod proxy_protocol;
+mod ratelimit;
+mod rotate;
+mod router;
+mod sitemap;
+mod tls;
 mod ws;
+mod xml;
 
 use std::net::SocketAddr;
+use std::sync::Arc;
+
+use activity::{Activity, HeaderTimer, TrackedIo};
+use proxy_protocol::Prefixed;
 
 // hyper stuff
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
-use tokio::net::TcpListener;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Duration, timeout};
+use tokio_rustls::TlsAcceptor;
 
 // loging
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
+
+// serves HTTP/1.1 or HTTP/2 on a connection until it closes or stays idle for too long
+async fn serve_http<I>(
+    io: I,
+    conn: handler::ConnInfo,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let limits = &config::get().limits;
+    let peer = conn.peer;
+    let activity = Activity::new();
+    let service = service_fn({
+        let activity = activity.clone();
+        move |req| handler::handle_request(req, conn.clone(), activity.start())
+    });
+
+    // HTTP/1.1 or HTTP/2, detected from the connection preface (or negotiated by ALPN)
+    let h2 = &config::get().http2;
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .keep_alive(true)
+        .timer(HeaderTimer::new(activity.clone()))
+        .header_read_timeout(
+            (limits.header_read_timeout > 0)
+                .then(|| Duration::from_secs(limits.header_read_timeout)),
+        )
+        .max_buf_size(limits.max_header_size.max(8192)); // hyper panics below 8192
+    builder
+        .http2()
+        .max_concurrent_streams(h2.max_concurrent_streams)
+        .max_header_list_size(h2.max_header_list_size)
+        .timer(TokioTimer::new()) // hyper panics without one when keep-alive is enabled
+        .keep_alive_interval(
+            (h2.keep_alive_interval > 0).then(|| Duration::from_secs(h2.keep_alive_interval)),
+        )
+        .enable_connect_protocol(); // RFC 8441, WebSockets over HTTP/2
+    if !h2.enabled {
+        builder = builder.http1_only();
+    }
+
+    let connection = builder.serve_connection_with_upgrades(
+        TokioIo::new(TrackedIo::new(io, activity.clone())),
+        service,
+    );
+    tokio::pin!(connection);
+
+    if limits.idle_timeout == 0 {
+        return connection.await;
+    }
+
+    // an idle connection is shut down gracefully, responses being written are completed
+    tokio::select! {
+        result = connection.as_mut() => return result,
+        _ = activity.idle(Duration::from_secs(limits.idle_timeout)) => {
+            debug!(%peer, "Closing idle connection");
+            connection.as_mut().graceful_shutdown();
+        }
+    }
+    connection.await
+}
+
+// serves HTTP on an accepted connection, after its PROXY header and TLS handshake if any
+async fn serve_connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    permit: OwnedSemaphorePermit,
+) {
+    // behind a TCP load balancer the client address comes from the PROXY header, the bytes
+    // read after it are the start of the TLS handshake or the HTTP request
+    let (peer, stream) = if config::get().proxy.mode == config::ProxyMode::ProxyProtocol {
+        match proxy_protocol::accept(&mut stream, peer).await {
+            Some((client, rest)) => (client, Prefixed::new(rest, stream)),
+            None => return,
+        }
+    } else {
+        (peer, Prefixed::new(Vec::new(), stream))
+    };
+
+    let conn = handler::ConnInfo {
+        peer,
+        tls: tls.is_some(),
+        permit: Arc::new(permit),
+    };
+
+    let result = match tls {
+        Some(acceptor) => {
+            let handshake = timeout(
+                Duration::from_secs(constants::TLS_HANDSHAKE_TIMEOUT),
+                acceptor.accept(stream),
+            )
+            .await;
+            match handshake {
+                Ok(Ok(stream)) => serve_http(stream, conn).await,
+                Ok(Err(e)) => {
+                    warn!(%peer, error = %e, "TLS handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    warn!(%peer, "TLS handshake timed out");
+                    return;
+                }
+            }
+        }
+        None => serve_http(stream, conn).await,
+    };
+
+    if let Err(err) = result {
+        // slow or silent clients are expected, not server errors
+        if err
+            .downcast_ref::<hyper::Error>()
+            .is_some_and(|e| e.is_timeout())
+        {
+            debug!(%peer, "Timed out reading request headers");
+            return;
+        }
+        error!(error = ?err, "Error serving connection");
+        eprintln!("Error serving connection: {err:?}");
+    }
+}
+
+// answers (or just closes) a connection accepted while max_connections are open
+async fn reject_connection(mut stream: TcpStream, peer: SocketAddr, tls: bool) {
+    metrics::inc(&metrics::CONNECTIONS_REJECTED);
+    debug!(%peer, "Too many connections, rejecting");
+
+    // a TLS client would not understand a plain text answer
+    if config::get().limits.when_full == config::WhenFull::Reject && !tls {
+        let _ = timeout(
+            Duration::from_secs(constants::REJECT_WRITE_TIMEOUT),
+            async {
+                stream.write_all(constants::REJECT_RESPONSE).await?;
+                stream.shutdown().await
+            },
+        )
+        .await;
+    }
+}
+
+// serves an accepted connection if one of the max_connections slots is free
+fn dispatch(
+    stream: TcpStream,
+    peer: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    connections: &Arc<Semaphore>,
+) {
+    match connections.clone().try_acquire_owned() {
+        Ok(permit) => tokio::spawn(serve_connection(stream, peer, tls, permit)),
+        Err(_) => tokio::spawn(reject_connection(stream, peer, tls.is_some())),
+    };
+}
+
+// accept fails when the process is out of file descriptors (EMFILE) or the system is
+// (ENFILE), retrying at once would spin until one is closed
+async fn accept_failed(e: &std::io::Error) {
+    error!(error = %e, "Failed to accept connection");
+    tokio::time::sleep(Duration::from_millis(constants::ACCEPT_RETRY_DELAY)).await;
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // `webrs healthcheck` probes a running server instead of starting one
+    if std::env::args().nth(1).as_deref() == Some("healthcheck") {
+        std::process::exit(health::run_healthcheck().await);
+    }
+
     println!("Starting server...");
 
-    // initialize logging and database
-    let _guard = log::init_logging()?;
+    // initialize configuration, metrics, logging and database
+    config::initialize()?;
+    metrics::initialize();
+    let _guards = log::init_logging()?;
     db::initialize().await?;
+    assets::initialize()?;
+    handler::initialize()?;
 
     let addr = SocketAddr::from(constants::MAIN_HOST);
     let listener = TcpListener::bind(addr).await?;
 
-    let mut sigint = signal(SignalKind::interrupt())?;
-    let mut sigterm = signal(SignalKind::terminate())?;
-
-    info!(
-        "[M] ==================================================== Listening on http://{} ====================================================",
-        addr
-    );
-    println!("Listening on http://{addr}");
+    // optional separate listener for /metrics, e.g. only reachable from the host
+    if let (true, Some(metrics_addr)) =
+        (config::get().metrics.enabled, config::get().metrics.listen)
+    {
+        let metrics_listener = TcpListener::bind(metrics_addr).await?;
+        info!(addr = %metrics_addr, "Metrics listening");
+        println!("Metrics listening on http://{metrics_addr}");
 
-    loop {
-        tokio::select! {
-            conn = listener.accept() => {
-                let (stream, _) = conn?;
+        tokio::spawn(async move {
+            loop {
+                let stream = match metrics_listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        accept_failed(&e).await;
+                        continue;
+                    }
+                };
                 let io = TokioIo::new(stream);
 
                 tokio::spawn(async move {
                     if let Err(err) = http1::Builder::new()
-                        .keep_alive(true)
-                        .serve_connection(io, service_fn(handler::handle_request))
-                        .with_upgrades()
+                        .serve_connection(io, service_fn(handler::handle_metrics))
                         .await
                     {
-                        error!("[M] Error serving connection: {:?}", err);
-                        eprintln!("Error serving connection: {err:?}");
+                        error!(error = ?err, "Error serving metrics connection");
                     }
                 });
+            }
+        });
+    }
+
+    // optional TLS listener, next to the plain HTTP one
+    let tls_cfg = &config::get().tls;
+    let tls = if tls_cfg.enabled {
+        let acceptor = tls::initialize(tls_cfg)?;
+        let tls_listener = TcpListener::bind(tls_cfg.listen).await?;
+        info!(addr = %tls_cfg.listen, "TLS listening");
+        println!("Listening on https://{}", tls_cfg.listen);
+        Some((tls_listener, acceptor))
+    } else {
+        None
+    };
+
+    // every open connection holds a permit, upgraded WebSockets included
+    let connections = Arc::new(Semaphore::new(config::get().limits.max_connections));
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    info!(
+        addr = %addr,
+        "==================================================== Listening ===================================================="
+    );
+    println!("Listening on http://{addr}");
+
+    loop {
+        tokio::select! {
+            conn = listener.accept() => match conn {
+                Ok((stream, peer)) => dispatch(stream, peer, None, &connections),
+                Err(e) => accept_failed(&e).await,
+            },
+            conn = async { tls.as_ref().unwrap().0.accept().await }, if tls.is_some() => match conn {
+                Ok((stream, peer)) => {
+                    let acceptor = tls.as_ref().map(|(_, acceptor)| acceptor.clone());
+                    dispatch(stream, peer, acceptor, &connections);
+                }
+                Err(e) => accept_failed(&e).await,
             },
             _ = sigint.recv() => {
-                info!("[M] SIGINT");
+                info!(signal = "SIGINT", "Shutdown");
                 println!("Shutdown signal received: SIGINT");
                 break;
             },
             _ = sigterm.recv() => {
-                info!("[M] SIGTERM");
+                info!(signal = "SIGTERM", "Shutdown");
                 println!("Shutdown signal received: SIGTERM");
                 break;
             }
@@ -70,3 +314,162 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::io::AsyncReadExt;
+    use tokio::time;
+
+    // accept loop on an ephemeral port with its own connection slots
+    async fn spawn_server(max_connections: usize) -> SocketAddr {
+        config::set_for_tests();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(Semaphore::new(max_connections));
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = listener.accept().await.unwrap();
+                dispatch(stream, peer, None, &connections);
+            }
+        });
+        addr
+    }
+
+    // reads until the server closes the connection, or fails after `limit`
+    async fn read_until_closed(stream: &mut TcpStream, limit: Duration) -> String {
+        let mut received = Vec::new();
+        let read = timeout(limit, async {
+            let mut buf = [0u8; 1024];
+            loop {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => received.extend_from_slice(&buf[..n]),
+                }
+            }
+        })
+        .await;
+        assert!(read.is_ok(), "connection still open after {limit:?}");
+        String::from_utf8_lossy(&received).into_owned()
+    }
+
+    #[tokio::test]
+    async fn serves_h2c_with_keep_alive() {
+        let addr = spawn_server(16).await;
+        assert!(config::get().http2.keep_alive_interval > 0);
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+
+        let request = hyper::Request::get(format!("http://{addr}/healthz"))
+            .body(http_body_util::Empty::<bytes::Bytes>::new())
+            .unwrap();
+        let response = timeout(Duration::from_secs(3), sender.send_request(request))
+            .await
+            .expect("no response after 3s")
+            .unwrap();
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn closes_connection_with_incomplete_headers() {
+        let addr = spawn_server(16).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\n")
+            .await
+            .unwrap();
+
+        read_until_closed(&mut stream, Duration::from_secs(3)).await;
+    }
+
+    #[tokio::test]
+    async fn header_timeout_is_not_reset_by_trickled_bytes() {
+        let addr = spawn_server(16).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let started = Instant::now();
+        let request = b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nX-Slow: aaaaaaaaaaaaaaaa";
+        for byte in request {
+            if stream.write_all(&[*byte]).await.is_err() {
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+            if started.elapsed() > Duration::from_secs(4) {
+                panic!("headers still being accepted after 4s");
+            }
+        }
+        read_until_closed(&mut stream, Duration::from_secs(3)).await;
+    }
+
+    #[tokio::test]
+    async fn closes_idle_keep_alive_connection() {
+        let addr = spawn_server(16).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        let response = read_until_closed(&mut stream, Duration::from_secs(4)).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+        // waiting for the next request is idle time, not header time
+        assert!(started.elapsed() >= Duration::from_millis(1800));
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_over_the_limit() {
+        let addr = spawn_server(2).await;
+
+        // two slow clients that never send anything hold both slots
+        let _slow = [
+            TcpStream::connect(addr).await.unwrap(),
+            TcpStream::connect(addr).await.unwrap(),
+        ];
+        time::sleep(Duration::from_millis(100)).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let response = read_until_closed(&mut stream, Duration::from_secs(1)).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn frees_the_slot_of_a_closed_connection() {
+        let addr = spawn_server(1).await;
+
+        let slow = TcpStream::connect(addr).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        drop(slow);
+        time::sleep(Duration::from_millis(100)).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_until_closed(&mut stream, Duration::from_secs(1)).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn refuses_body_over_the_limit() {
+        let addr = spawn_server(16).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST /admin/announce HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4096\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let response = read_until_closed(&mut stream, Duration::from_secs(4)).await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+    }
+}
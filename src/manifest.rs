@@ -0,0 +1,137 @@
+/*  Asset manifest
+    - Shared with build.rs (included with #[path]), so the embedded manifest and dev mode
+      name, type and template assets the same way
+    - An asset is named by its path in ASSETS_DIR, templates refer to it as {{asset:<name>}}
+      and get the url it is served at, {{integrity:<name>}} gets its SRI digest (sha384)
+*/
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha2::{Digest, Sha384};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const ASSETS_DIR: &str = "assets";
+
+// one file of ASSETS_DIR, the manifest generated by build.rs is a list of these
+#[derive(Debug)]
+pub struct ManifestEntry {
+    pub name: &'static str,         // logical name, path relative to ASSETS_DIR
+    pub url: &'static str,          // served at
+    pub hash: &'static str,         // sha256 of the bytes, hex
+    pub integrity: &'static str,    // "sha384-<base64>", for integrity attributes and the CSP
+    pub content_type: &'static str, // MIME type
+    pub cache_control: &'static str,
+    pub bytes: &'static [u8], // templated files have their placeholders replaced
+    pub br: Option<&'static [u8]>, // precompressed variants, only kept when smaller
+    pub gzip: Option<&'static [u8]>,
+}
+
+pub fn content_type(name: &str) -> &'static str {
+    let ext = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "application/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("woff") => "font/woff",
+        _ => "application/octet-stream",
+    }
+}
+
+// files of `dir` and its subdirectories as (logical name, path), sorted by name
+pub fn walk(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    fn visit(dir: &Path, prefix: &str, out: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                visit(&entry.path(), &format!("{name}/"), out)?;
+            } else {
+                out.push((name, entry.path()));
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    visit(dir, "", &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+// what a template can know of an asset
+#[derive(Clone, Debug)]
+pub struct AssetRef {
+    pub url: String,
+    pub integrity: String,
+}
+
+// Subresource Integrity digest of `bytes`, as in an integrity attribute or a CSP source
+pub fn integrity(bytes: &[u8]) -> String {
+    format!("sha384-{}", STANDARD.encode(Sha384::digest(bytes)))
+}
+
+// scripts can be allowed by the CSP with their digest
+pub fn is_script(content_type: &str) -> bool {
+    content_type.starts_with("application/javascript")
+        || content_type.starts_with("text/javascript")
+}
+
+// true if `contents` has placeholders to replace
+pub fn is_templated(contents: &[u8]) -> bool {
+    let contents = String::from_utf8_lossy(contents);
+    contents.contains("{{asset:")
+        || contents.contains("{{integrity:")
+        || contents.contains("{{BUILD_VERSION}}")
+}
+
+// replaces {{asset:<name>}} by the url of the asset, {{integrity:<name>}} by its digest and
+// {{BUILD_VERSION}} by the version
+pub fn substitute(
+    contents: &str,
+    ref_of: impl Fn(&str) -> Option<AssetRef>,
+) -> Result<String, String> {
+    let contents = contents.replace("{{BUILD_VERSION}}", env!("CARGO_PKG_VERSION"));
+    let mut out = String::with_capacity(contents.len());
+    let mut rest = contents.as_str();
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let placeholder = &rest[start + 2..];
+        let Some((kind, after)) = ["asset:", "integrity:"]
+            .iter()
+            .find_map(|kind| Some((*kind, placeholder.strip_prefix(kind)?)))
+        else {
+            // not ours, e.g. a literal "{{" in a script
+            out.push_str("{{");
+            rest = placeholder;
+            continue;
+        };
+        let end = after
+            .find("}}")
+            .ok_or_else(|| format!("unclosed {{{{{kind} placeholder"))?;
+        let name = after[..end].trim();
+        let asset = ref_of(name).ok_or_else(|| format!("unknown asset '{name}'"))?;
+        out.push_str(if kind == "asset:" {
+            &asset.url
+        } else {
+            &asset.integrity
+        });
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
@@ -0,0 +1,217 @@
+use crate::log;
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        // buckets are not cumulative here, they are summed up when rendering
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+
+// (route, status) -> number of requests
+static REQUESTS: Lazy<DashMap<(&'static str, u16), AtomicU64>> = Lazy::new(DashMap::new);
+
+pub static CONNECTIONS_REJECTED: AtomicU64 = AtomicU64::new(0);
+pub static RATE_LIMITED: AtomicU64 = AtomicU64::new(0);
+pub static CSP_REPORTS: AtomicU64 = AtomicU64::new(0);
+pub static WS_BROADCASTS: AtomicU64 = AtomicU64::new(0);
+pub static WS_DROPPED_FULL: AtomicU64 = AtomicU64::new(0);
+pub static WS_DROPPED_CLOSED: AtomicU64 = AtomicU64::new(0);
+pub static DB_FLUSH_FAILURES: AtomicU64 = AtomicU64::new(0);
+pub static DB_FLUSH_LATENCY: Histogram = Histogram::new();
+pub static RENDER_LATENCY: Histogram = Histogram::new();
+
+pub fn initialize() {
+    Lazy::force(&START);
+}
+
+#[inline(always)]
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_request(route: &'static str, status: u16) {
+    REQUESTS
+        .entry((route, status))
+        .or_insert_with(|| AtomicU64::new(0))
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+// renders every metric in the Prometheus text exposition format
+pub fn render(ws_users: usize, event_subscribers: usize, db_messages: usize) -> String {
+    let mut out = String::with_capacity(4096);
+
+    let _ = writeln!(
+        out,
+        "# HELP webrs_http_requests_total HTTP requests by route and status."
+    );
+    let _ = writeln!(out, "# TYPE webrs_http_requests_total counter");
+    let mut requests: Vec<_> = REQUESTS
+        .iter()
+        .map(|e| (*e.key(), e.value().load(Ordering::Relaxed)))
+        .collect();
+    requests.sort_unstable();
+    for ((route, status), count) in requests {
+        let _ = writeln!(
+            out,
+            "webrs_http_requests_total{{route=\"{route}\",status=\"{status}\"}} {count}"
+        );
+    }
+
+    let gauge = |out: &mut String, name: &str, help: &str, value: String| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {value}");
+    };
+    let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+    };
+
+    counter(
+        &mut out,
+        "webrs_connections_rejected_total",
+        "Connections refused because max_connections were open.",
+        &CONNECTIONS_REJECTED,
+    );
+    counter(
+        &mut out,
+        "webrs_rate_limited_total",
+        "Requests answered 429 by the per-IP rate limit.",
+        &RATE_LIMITED,
+    );
+    counter(
+        &mut out,
+        "webrs_csp_reports_total",
+        "CSP violations reported by browsers, duplicates excluded.",
+        &CSP_REPORTS,
+    );
+
+    gauge(
+        &mut out,
+        "webrs_ws_connected_users",
+        "Users connected to the WebSocket hub.",
+        ws_users.to_string(),
+    );
+    counter(
+        &mut out,
+        "webrs_ws_broadcasts_total",
+        "Messages broadcast to the WebSocket hub.",
+        &WS_BROADCASTS,
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP webrs_ws_dropped_sends_total Broadcast sends that did not reach a user."
+    );
+    let _ = writeln!(out, "# TYPE webrs_ws_dropped_sends_total counter");
+    let _ = writeln!(
+        out,
+        "webrs_ws_dropped_sends_total{{reason=\"full\"}} {}",
+        WS_DROPPED_FULL.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "webrs_ws_dropped_sends_total{{reason=\"closed\"}} {}",
+        WS_DROPPED_CLOSED.load(Ordering::Relaxed)
+    );
+
+    gauge(
+        &mut out,
+        "webrs_events_subscribers",
+        "Open Server-Sent Events streams of /events.",
+        event_subscribers.to_string(),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP webrs_log_dropped_lines_total Log lines dropped because a log sink fell behind."
+    );
+    let _ = writeln!(out, "# TYPE webrs_log_dropped_lines_total counter");
+    for (sink, dropped) in log::dropped_lines() {
+        let _ = writeln!(
+            out,
+            "webrs_log_dropped_lines_total{{sink=\"{sink}\"}} {dropped}"
+        );
+    }
+
+    gauge(
+        &mut out,
+        "webrs_db_messages",
+        "Messages held in the message store.",
+        db_messages.to_string(),
+    );
+    counter(
+        &mut out,
+        "webrs_db_flush_failures_total",
+        "Failed writes of the message store to disk.",
+        &DB_FLUSH_FAILURES,
+    );
+    DB_FLUSH_LATENCY.render(
+        &mut out,
+        "webrs_db_flush_duration_seconds",
+        "Time spent writing the message store to disk.",
+    );
+    RENDER_LATENCY.render(
+        &mut out,
+        "webrs_render_duration_seconds",
+        "Time spent rendering the index page.",
+    );
+
+    gauge(
+        &mut out,
+        "webrs_uptime_seconds",
+        "Seconds since the process started.",
+        START.elapsed().as_secs_f64().to_string(),
+    );
+
+    out
+}
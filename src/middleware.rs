@@ -0,0 +1,441 @@
+/*  Middlewares of the main listener, in the order they wrap a request
+    - RequestId: id of the request, the X-Request-Id a trusted proxy sent or a new one,
+      recorded in its span and sent back in X-Request-Id
+    - ClientIp: client address, the peer or what a trusted proxy forwarded
+    - AccessLog: the request log line and the request metrics
+    - SecurityHeaders: headers of the config added to every response unless the route set
+      its own, with the nonce of the request and the digests of the scripts
+    - Head: HEAD is routed as GET, the body is dropped once its length is known
+    - ErrorPages: error responses without a body get an HTML or JSON one
+    - RateLimit: per-IP token bucket, 429 once it is empty (only when enabled)
+    - BodyLimit: bodies announced over limits.max_body_size are refused
+    - Compression: dynamic responses compressed on the fly
+*/
+use crate::assets;
+use crate::compress;
+use crate::config::{self, SecurityConfig};
+use crate::constants;
+use crate::crypt;
+use crate::errors;
+use crate::handler::{ConnInfo, RequestBody};
+use crate::ip;
+use crate::metrics;
+use crate::ratelimit::RateLimiter;
+use crate::router::{BoxFuture, HandlerResult, Middleware, Next, RouteLabel};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Body;
+use hyper::header::{
+    ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+    HeaderName, HeaderValue, RETRY_AFTER, STRICT_TRANSPORT_SECURITY,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use std::borrow::Cow;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use tracing::{Span, field, info};
+
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// address of the client, as logged and rate limited
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+// client address of a request that went through ClientIp, or its TCP peer
+pub fn client_ip<B>(req: &Request<B>) -> IpAddr {
+    match (
+        req.extensions().get::<ClientIp>(),
+        req.extensions().get::<ConnInfo>(),
+    ) {
+        (Some(ip), _) => ip.0,
+        (None, Some(conn)) => conn.peer.ip(),
+        (None, None) => IpAddr::from([0, 0, 0, 0]),
+    }
+}
+
+// id of the request, as logged and shown on error pages
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+// id of a request that went through RequestId, empty otherwise
+pub fn request_id<B>(req: &Request<B>) -> String {
+    req.extensions()
+        .get::<RequestId>()
+        .map_or_else(String::new, |id| id.0.clone())
+}
+
+// ids of other hops are kept when they are safe to log and send back
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= constants::REQUEST_ID_MAX_LENGTH
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+pub struct RequestIdLayer;
+
+impl Middleware for RequestIdLayer {
+    fn call<'a>(
+        &'a self,
+        mut req: Request<RequestBody>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, HandlerResult> {
+        // as for ClientIp, anyone else could forge the ids of the logs or collide with them
+        let trusted = req
+            .extensions()
+            .get::<ConnInfo>()
+            .is_some_and(|conn| ip::is_trusted(conn.peer.ip()));
+        let id = req
+            .headers()
+            .get(X_REQUEST_ID)
+            .filter(|_| trusted)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| is_valid_request_id(id))
+            .map_or_else(crypt::generate_request_id, str::to_string);
+        Span::current().record("id", field::display(&id));
+        req.extensions_mut().insert(RequestId(id.clone()));
+        Box::pin(async move {
+            let mut res = next.run(req).await?;
+            if let Ok(id) = HeaderValue::from_str(&id) {
+                res.headers_mut().insert(X_REQUEST_ID, id);
+            }
+            Ok(res)
+        })
+    }
+}
+
+pub struct ClientIpLayer;
+
+impl Middleware for ClientIpLayer {
+    fn call<'a>(
+        &'a self,
+        mut req: Request<RequestBody>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, HandlerResult> {
+        // forwarding headers are only honored when the peer is a trusted proxy
+        let peer = req
+            .extensions()
+            .get::<ConnInfo>()
+            .map(|conn| conn.peer.ip());
+        if let Some(peer) = peer {
+            let ip = ip::client_ip(req.headers(), peer);
+            Span::current().record("ip", field::display(ip));
+            req.extensions_mut().insert(ClientIp(ip));
+        }
+        next.run(req)
+    }
+}
+
+pub struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn call<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            let ua = req
+                .headers()
+                .get("User-Agent")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("Unknown User-Agent");
+
+            // log the request, ip, method and path are fields of the span
+            info!(user_agent = ua, "Request");
+
+            let route = req
+                .extensions()
+                .get::<RouteLabel>()
+                .map_or("other", |l| l.0);
+            let result = next.run(req).await;
+            if let Ok(res) = &result {
+                metrics::record_request(route, res.status().as_u16());
+            }
+            result
+        })
+    }
+}
+
+// nonce of the request, for the templates and the {nonce} of the security headers
+#[derive(Clone, Debug)]
+pub struct Nonce(pub String);
+
+// nonce of a request that went through SecurityHeaders, or a fresh one
+pub fn nonce<B>(req: &Request<B>) -> String {
+    match req.extensions().get::<Nonce>() {
+        Some(nonce) => nonce.0.clone(),
+        None => crypt::generate_nonce_base64(constants::NONCE_LENGTH),
+    }
+}
+
+// header values may contain {nonce}, replaced once the nonce of the request is known, and
+// {script_hashes}, replaced by the 'sha384-...' sources of the scripts
+type Headers = Vec<(HeaderName, String)>;
+
+// the headers of SecurityConfig, a route that sets one of them itself keeps its own
+pub struct SecurityHeaders {
+    default: Headers,
+    routes: Vec<(String, Headers)>, // by path prefix, longest first
+}
+
+impl SecurityHeaders {
+    // with `reports`, every CSP reports its violations to POST /csp-report
+    pub fn new(cfg: &SecurityConfig, reports: bool) -> Result<Self, String> {
+        let mut default = Vec::new();
+        set_header(&mut default, "content-security-policy", &cfg.csp)?;
+        set_header(&mut default, "report-to", &cfg.report_to)?;
+        if reports {
+            let endpoint = format!(
+                "{}=\"{}\"",
+                constants::CSP_REPORT_GROUP,
+                constants::CSP_REPORT_PATH
+            );
+            set_header(&mut default, "reporting-endpoints", &endpoint)?;
+        }
+        for (name, value) in &cfg.headers {
+            set_header(&mut default, name, value)?;
+        }
+
+        let mut routes = Vec::new();
+        for (prefix, overrides) in &cfg.routes {
+            let mut headers = default.clone();
+            for (name, value) in overrides {
+                set_header(&mut headers, name, value)?;
+            }
+            routes.push((prefix.trim_end_matches('/').to_string(), headers));
+        }
+        if reports {
+            add_reporting(&mut default);
+            for (_, headers) in &mut routes {
+                add_reporting(headers);
+            }
+        }
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Ok(Self { default, routes })
+    }
+
+    fn headers(&self, path: &str) -> &Headers {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .map_or(&self.default, |(_, headers)| headers)
+    }
+}
+
+// report-uri for the browsers that only know it, report-to for the others
+fn add_reporting(headers: &mut Headers) {
+    for (name, value) in headers.iter_mut() {
+        if *name == CONTENT_SECURITY_POLICY && !value.contains("report-uri") {
+            value.push_str(&format!(
+                "; report-uri {}; report-to {}",
+                constants::CSP_REPORT_PATH,
+                constants::CSP_REPORT_GROUP
+            ));
+        }
+    }
+}
+
+// sets, or with an empty value removes, header `name` of `headers`
+fn set_header(headers: &mut Headers, name: &str, value: &str) -> Result<(), String> {
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|e| format!("security header {name:?}: {e}"))?;
+    headers.retain(|(n, _)| *n != name);
+    if value.is_empty() {
+        return Ok(());
+    }
+    HeaderValue::from_str(&value.replace("{nonce}", "").replace("{script_hashes}", ""))
+        .map_err(|e| format!("security header {name}: {e}"))?;
+    headers.push((name, value.to_string()));
+    Ok(())
+}
+
+impl Middleware for SecurityHeaders {
+    fn call<'a>(
+        &'a self,
+        mut req: Request<RequestBody>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            let tls = req.extensions().get::<ConnInfo>().is_some_and(|c| c.tls);
+            let headers = self.headers(req.uri().path());
+            let nonce = headers
+                .iter()
+                .any(|(_, value)| value.contains("{nonce}"))
+                .then(|| crypt::generate_nonce_base64(constants::NONCE_LENGTH));
+            if let Some(nonce) = &nonce {
+                req.extensions_mut().insert(Nonce(nonce.clone()));
+            }
+
+            let mut res = next.run(req).await?;
+            let not_modified = res.status() == StatusCode::NOT_MODIFIED;
+            let res_headers = res.headers_mut();
+            for (name, value) in headers {
+                let mut value = Cow::Borrowed(value.as_str());
+                match &nonce {
+                    // a cache would store the new nonce with the page it already has
+                    Some(_) if not_modified && value.contains("{nonce}") => continue,
+                    Some(nonce) => value = value.replace("{nonce}", nonce).into(),
+                    None => {}
+                }
+                // the digests change with the scripts, in dev mode as soon as one is edited
+                if value.contains("{script_hashes}") {
+                    value = value
+                        .replace("{script_hashes}", &assets::get().script_hashes())
+                        .into();
+                }
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    res_headers.entry(name).or_insert(value);
+                }
+            }
+
+            // HSTS is only meaningful (and only honored by browsers) over TLS
+            let hsts = &config::get().tls.hsts;
+            if tls
+                && let Ok(hsts) = HeaderValue::from_str(hsts)
+                && !hsts.is_empty()
+            {
+                res_headers.entry(STRICT_TRANSPORT_SECURITY).or_insert(hsts);
+            }
+            Ok(res)
+        })
+    }
+}
+
+pub struct Head;
+
+impl Middleware for Head {
+    fn call<'a>(
+        &'a self,
+        mut req: Request<RequestBody>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, HandlerResult> {
+        if req.method() != Method::HEAD {
+            return next.run(req);
+        }
+        *req.method_mut() = Method::GET;
+        Box::pin(async move {
+            let (mut parts, body) = next.run(req).await?.into_parts();
+            let len = body
+                .collect()
+                .await
+                .map(|c| c.to_bytes().len())
+                .unwrap_or(0);
+            // a 304 or 204 has no length to announce
+            if parts.status.is_success() && parts.status != StatusCode::NO_CONTENT {
+                parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+            }
+            Ok(Response::from_parts(parts, Full::new(Bytes::new())))
+        })
+    }
+}
+
+pub struct ErrorPages;
+
+impl Middleware for ErrorPages {
+    fn call<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, HandlerResult> {
+        let json = errors::prefers_json(req.headers(), req.uri().path());
+        let id = request_id(&req);
+        Box::pin(async move {
+            let mut res = next.run(req).await?;
+            let status = res.status();
+            // a body of the route, e.g. the JSON errors of the admin API, is kept
+            if !(status.is_client_error() || status.is_server_error())
+                || res.body().size_hint().exact() != Some(0)
+            {
+                return Ok(res);
+            }
+
+            let (content_type, body) = errors::body(status, &id, json);
+            let headers = res.headers_mut();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            headers
+                .entry(CACHE_CONTROL)
+                .or_insert(HeaderValue::from_static("no-store"));
+            headers.remove(CONTENT_LENGTH);
+            *res.body_mut() = Full::new(body);
+            Ok(res)
+        })
+    }
+}
+
+pub struct RateLimit(RateLimiter);
+
+impl RateLimit {
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        Self(RateLimiter::new(per_second, burst))
+    }
+}
+
+impl Middleware for RateLimit {
+    fn call<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, HandlerResult> {
+        if self.0.check(client_ip(&req)) {
+            return next.run(req);
+        }
+        metrics::RATE_LIMITED.fetch_add(1, Ordering::Relaxed);
+        info!(status = 429, "429 Too Many Requests");
+        let res = Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, "1")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+pub struct BodyLimit;
+
+impl Middleware for BodyLimit {
+    fn call<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, HandlerResult> {
+        // a body announced over the limit is refused before anything reads it,
+        // a chunked one fails to read once it goes over (RequestBody is Limited)
+        let max_body_size = config::get().limits.max_body_size;
+        let too_large = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .is_some_and(|len| len > max_body_size as u64);
+        if !too_large {
+            return next.run(req);
+        }
+        info!(status = 413, "413 Payload Too Large");
+        let mut res = Response::new(Full::new(Bytes::new()));
+        *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+pub struct Compression;
+
+impl Middleware for Compression {
+    fn call<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, HandlerResult> {
+        let accept_encoding = req.headers().get(ACCEPT_ENCODING).cloned();
+        Box::pin(async move {
+            let res = next.run(req).await?;
+            Ok(compress::dynamic(accept_encoding.as_ref(), res).await)
+        })
+    }
+}
@@ -0,0 +1,285 @@
+/*  Posts
+    - The content pages under POSTS_PATH with a date, newest first, listed at POSTS_PATH and
+      by tag at /tags/<tag>, the most recent ones in an Atom 1.0 and an RSS 2.0 feed
+    - The feeds are built once per generation of the asset store, they only change when dev
+      mode reloads the content
+    - Drafts only exist in dev mode (build.rs leaves them out), they are marked as such
+*/
+use crate::assets;
+use crate::conditional;
+use crate::config;
+use crate::constants;
+use crate::content::{self, Page};
+use crate::feed::Feed;
+use crate::xml;
+
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+pub struct Feeds {
+    generation: u64, // of the asset store they were built from
+    pub atom: Feed,
+    pub rss: Feed,
+}
+
+static FEEDS: Lazy<Mutex<Option<Arc<Feeds>>>> = Lazy::new(|| Mutex::new(None));
+
+// the Atom and RSS feeds of the current posts
+pub fn feeds() -> Arc<Feeds> {
+    let store = assets::get();
+    let generation = store.generation();
+    if let Some(feeds) = FEEDS.lock().unwrap().as_ref()
+        && feeds.generation == generation
+    {
+        return feeds.clone();
+    }
+
+    // dated by the build, or the reload in dev, the posts only have the day of their last change
+    let posts = all(store.pages());
+    let modified = store.loaded_at();
+    let feed = |body: String| {
+        let hash = format!("{:x}", Sha256::digest(body.as_bytes()));
+        Feed {
+            etag: format!("\"{}\"", &hash[..16]),
+            modified,
+            body: Bytes::from(body),
+        }
+    };
+    let feeds = Arc::new(Feeds {
+        generation,
+        atom: feed(atom(&posts)),
+        rss: feed(rss(&posts)),
+    });
+    *FEEDS.lock().unwrap() = Some(feeds.clone());
+    feeds
+}
+
+pub fn is_post(page: &Page) -> bool {
+    page.date.is_some()
+        && page
+            .path
+            .strip_prefix(constants::POSTS_PATH)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+// the posts among `pages`, newest first
+pub fn all(pages: Vec<Arc<Page>>) -> Vec<Arc<Page>> {
+    let mut posts: Vec<Arc<Page>> = pages.into_iter().filter(|p| is_post(p)).collect();
+    posts.sort_by(|a, b| b.date.cmp(&a.date).then_with(|| a.path.cmp(&b.path)));
+    posts
+}
+
+// the posts of `tag`, newest first
+pub fn tagged(posts: &[Arc<Page>], tag: &str) -> Vec<Arc<Page>> {
+    posts
+        .iter()
+        .filter(|post| post.tags.iter().any(|t| t == tag))
+        .cloned()
+        .collect()
+}
+
+// every tag of `posts`, sorted
+pub fn tags(posts: &[Arc<Page>]) -> Vec<&str> {
+    let mut tags: Vec<&str> = posts
+        .iter()
+        .flat_map(|post| post.tags.iter().map(String::as_str))
+        .collect();
+    tags.sort_unstable();
+    tags.dedup();
+    tags
+}
+
+// YYYY-MM-DD of the most recent change of `posts`
+pub fn lastmod(posts: &[Arc<Page>]) -> Option<&str> {
+    posts.iter().map(|post| post.lastmod.as_str()).max()
+}
+
+fn tag_links(tags: impl IntoIterator<Item = impl AsRef<str>>) -> String {
+    tags.into_iter()
+        .map(|tag| {
+            let tag = xml::escape(tag.as_ref());
+            format!("<a href=\"/tags/{tag}\">{tag}</a>")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// date and tags shown under the title of a post
+pub fn meta(post: &Page) -> String {
+    let date = xml::escape(post.date.as_deref().unwrap_or(""));
+    let mut meta = format!("<p class=\"post-meta\"><time datetime=\"{date}\">{date}</time>");
+    if !post.tags.is_empty() {
+        let _ = write!(meta, " · {}", tag_links(&post.tags));
+    }
+    if post.draft {
+        meta.push_str(" · draft");
+    }
+    meta.push_str("</p>");
+    meta
+}
+
+// HTML list of `posts`, then the tags of all of them and the feeds
+pub fn listing(posts: &[Arc<Page>], all: &[Arc<Page>]) -> String {
+    let mut html = String::from("<ul class=\"post-list\">\n");
+    for post in posts {
+        let date = xml::escape(post.date.as_deref().unwrap_or(""));
+        let _ = write!(
+            html,
+            "<li><time datetime=\"{date}\">{date}</time> <a href=\"{}\">{}</a>",
+            xml::escape(&post.path),
+            xml::escape(&post.title)
+        );
+        if !post.description.is_empty() {
+            let _ = write!(html, " - {}", xml::escape(&post.description));
+        }
+        html.push_str("</li>\n");
+    }
+    html.push_str("</ul>\n");
+
+    let tags = tags(all);
+    if !tags.is_empty() {
+        let _ = writeln!(html, "<p>Tags: {}</p>", tag_links(tags));
+    }
+    let _ = writeln!(
+        html,
+        "<p>Feeds: <a href=\"{}\">Atom</a>, <a href=\"{}\">RSS</a></p>",
+        constants::POSTS_ATOM_PATH,
+        constants::POSTS_RSS_PATH
+    );
+    html
+}
+
+fn base_url() -> &'static str {
+    config::get().site.url.trim_end_matches('/')
+}
+
+fn rfc3339(date: &str) -> String {
+    format!("{date}T00:00:00Z")
+}
+
+fn rfc822(date: &str) -> String {
+    conditional::http_date(UNIX_EPOCH + Duration::from_secs(content::timestamp_of(date)))
+}
+
+// Atom 1.0 feed of the most recent posts
+fn atom(posts: &[Arc<Page>]) -> String {
+    let site = &config::get().site;
+    let base = xml::escape(base_url());
+    let posts = &posts[..posts.len().min(constants::POSTS_FEED_ENTRIES)];
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    let _ = write!(
+        out,
+        concat!(
+            "<feed xmlns=\"http://www.w3.org/2005/Atom\" xml:base=\"{base}/\">\n",
+            "  <title>{title}</title>\n",
+            "  <link href=\"{base}{atom}\" rel=\"self\" type=\"application/atom+xml\"/>\n",
+            "  <link href=\"{base}{posts}\" rel=\"alternate\" type=\"text/html\"/>\n",
+            "  <id>{base}{posts}</id>\n",
+            "  <updated>{updated}</updated>\n",
+            "  <author><name>{author}</name></author>\n",
+        ),
+        base = base,
+        title = xml::escape(&site.title),
+        atom = constants::POSTS_ATOM_PATH,
+        posts = constants::POSTS_PATH,
+        updated = rfc3339(lastmod(posts).unwrap_or("1970-01-01")),
+        author = xml::escape(&site.author),
+    );
+    for post in posts {
+        let url = format!("{base}{}", xml::escape(&post.path));
+        let _ = write!(
+            out,
+            concat!(
+                "  <entry>\n",
+                "    <title>{title}</title>\n",
+                "    <link href=\"{url}\" rel=\"alternate\" type=\"text/html\"/>\n",
+                "    <id>{url}</id>\n",
+                "    <published>{published}</published>\n",
+                "    <updated>{updated}</updated>\n",
+            ),
+            title = xml::escape(&post.title),
+            url = url,
+            published = rfc3339(post.date.as_deref().unwrap_or(&post.lastmod)),
+            updated = rfc3339(&post.lastmod),
+        );
+        if !post.description.is_empty() {
+            let _ = writeln!(
+                out,
+                "    <summary>{}</summary>",
+                xml::escape(&post.description)
+            );
+        }
+        for tag in &post.tags {
+            let _ = writeln!(out, "    <category term=\"{}\"/>", xml::escape(tag));
+        }
+        let _ = write!(
+            out,
+            "    <content type=\"html\">{}</content>\n  </entry>\n",
+            xml::escape(&post.html)
+        );
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+// RSS 2.0 feed of the most recent posts
+fn rss(posts: &[Arc<Page>]) -> String {
+    let site = &config::get().site;
+    let base = xml::escape(base_url());
+    let posts = &posts[..posts.len().min(constants::POSTS_FEED_ENTRIES)];
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = write!(
+        out,
+        concat!(
+            "<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n",
+            "  <channel>\n",
+            "    <title>{title}</title>\n",
+            "    <link>{base}{posts}</link>\n",
+            "    <description>Posts of {title}</description>\n",
+            "    <atom:link href=\"{base}{rss}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+        ),
+        base = base,
+        title = xml::escape(&site.title),
+        posts = constants::POSTS_PATH,
+        rss = constants::POSTS_RSS_PATH,
+    );
+    if let Some(lastmod) = lastmod(posts) {
+        let _ = writeln!(
+            out,
+            "    <lastBuildDate>{}</lastBuildDate>",
+            rfc822(lastmod)
+        );
+    }
+    for post in posts {
+        let url = format!("{base}{}", xml::escape(&post.path));
+        let _ = write!(
+            out,
+            concat!(
+                "    <item>\n",
+                "      <title>{title}</title>\n",
+                "      <link>{url}</link>\n",
+                "      <guid isPermaLink=\"true\">{url}</guid>\n",
+                "      <pubDate>{published}</pubDate>\n",
+            ),
+            title = xml::escape(&post.title),
+            url = url,
+            published = rfc822(post.date.as_deref().unwrap_or(&post.lastmod)),
+        );
+        for tag in &post.tags {
+            let _ = writeln!(out, "      <category>{}</category>", xml::escape(tag));
+        }
+        let _ = write!(
+            out,
+            "      <description>{}</description>\n    </item>\n",
+            xml::escape(&post.html)
+        );
+    }
+    out.push_str("  </channel>\n</rss>\n");
+    out
+}
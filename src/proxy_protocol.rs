@@ -0,0 +1,463 @@
+/*  HAProxy PROXY protocol v1 and v2
+    - See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+    - The start of the connection is read into a buffer until it is known to hold a header
+      or not, the bytes read after the header (all of them without one) are handed to hyper
+      first through Prefixed
+*/
+use crate::constants;
+use crate::ip;
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::time::{Duration, timeout};
+use tracing::{debug, warn};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107; // including the trailing CRLF
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProxyHeader {
+    Absent,              // the connection does not start with a PROXY header
+    Local,               // v2 LOCAL or v1 UNKNOWN, the peer address stands
+    Proxied(SocketAddr), // source address of the proxied connection
+}
+
+// what the bytes read so far say about the header
+#[derive(Debug, PartialEq, Eq)]
+enum Parsed {
+    Incomplete,                 // more bytes are needed to tell
+    Header(ProxyHeader, usize), // the header and its length, 0 when absent
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// true while `data` could still be the start of a v1 or v2 header
+fn may_be_header(data: &[u8]) -> bool {
+    let v1 = &V1_PREFIX[..data.len().min(V1_PREFIX.len())];
+    let v2 = &V2_SIGNATURE[..data.len().min(V2_SIGNATURE.len())];
+    data.starts_with(v1) || data.starts_with(v2)
+}
+
+fn parse(data: &[u8]) -> io::Result<Parsed> {
+    if !may_be_header(data) {
+        return Ok(Parsed::Header(ProxyHeader::Absent, 0));
+    }
+
+    if data.starts_with(V2_SIGNATURE) {
+        if data.len() < V2_HEADER_LEN {
+            return Ok(Parsed::Incomplete);
+        }
+        let len = V2_HEADER_LEN + u16::from_be_bytes([data[14], data[15]]) as usize;
+        if len > constants::PROXY_V2_MAX_LEN {
+            return Err(invalid("PROXY v2 header too long"));
+        }
+        if data.len() < len {
+            return Ok(Parsed::Incomplete);
+        }
+        let header = parse_v2(&data[..V2_HEADER_LEN], &data[V2_HEADER_LEN..len])?;
+        return Ok(Parsed::Header(header, len));
+    }
+
+    if data.starts_with(V1_PREFIX) {
+        // v1: a single line, up to and including CRLF
+        let end = data.windows(2).position(|w| w == b"\r\n");
+        return match end {
+            Some(end) if end + 2 <= V1_MAX_LEN => {
+                Ok(Parsed::Header(parse_v1(&data[..end])?, end + 2))
+            }
+            None if data.len() < V1_MAX_LEN => Ok(Parsed::Incomplete),
+            _ => Err(invalid("PROXY v1 header too long")),
+        };
+    }
+
+    // a prefix of one of the signatures
+    Ok(Parsed::Incomplete)
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<ProxyHeader> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::Local),
+        [
+            "PROXY",
+            proto @ ("TCP4" | "TCP6"),
+            src,
+            _dst,
+            src_port,
+            _dst_port,
+        ] => {
+            let ip: IpAddr = src
+                .parse()
+                .map_err(|_| invalid("PROXY v1 invalid source address"))?;
+            if (*proto == "TCP4") != ip.is_ipv4() {
+                return Err(invalid("PROXY v1 address does not match protocol"));
+            }
+            let port: u16 = src_port
+                .parse()
+                .map_err(|_| invalid("PROXY v1 invalid source port"))?;
+            Ok(ProxyHeader::Proxied(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("PROXY v1 malformed header")),
+    }
+}
+
+// `block` is the address block (and any TLVs) announced by the length of `header`
+fn parse_v2(header: &[u8], block: &[u8]) -> io::Result<ProxyHeader> {
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    let family = header[13];
+
+    if version != 2 {
+        return Err(invalid("PROXY v2 unsupported version"));
+    }
+
+    match command {
+        0x0 => return Ok(ProxyHeader::Local),
+        0x1 => {}
+        _ => return Err(invalid("PROXY v2 unsupported command")),
+    }
+
+    match family {
+        // TCP over IPv4: src addr (4), dst addr (4), src port (2), dst port (2)
+        0x11 if block.len() >= 12 => {
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+            Ok(ProxyHeader::Proxied(SocketAddr::new(ip.into(), port)))
+        }
+        // TCP over IPv6: src addr (16), dst addr (16), src port (2), dst port (2)
+        0x21 if block.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&block[..16]);
+            let port = u16::from_be_bytes([block[32], block[33]]);
+            Ok(ProxyHeader::Proxied(SocketAddr::new(
+                Ipv6Addr::from(octets).into(),
+                port,
+            )))
+        }
+        0x11 | 0x21 => Err(invalid("PROXY v2 address block too short")),
+        // UNSPEC, UDP or unix sockets carry no usable client address
+        _ => Ok(ProxyHeader::Local),
+    }
+}
+
+// reads the start of the connection until it is known to hold a header or not, returns
+// the header and the bytes read after it
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> io::Result<(ProxyHeader, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(V1_MAX_LEN);
+    loop {
+        if let Parsed::Header(header, len) = parse(&buf)? {
+            return Ok((header, buf.split_off(len)));
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+// returns the client address of the connection and the bytes read after the header, or
+// None if it must be closed: only trusted peers may send a header, and a header that is
+// sent must be valid
+pub async fn accept<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    peer: SocketAddr,
+) -> Option<(SocketAddr, Vec<u8>)> {
+    let header = timeout(
+        Duration::from_secs(constants::PROXY_HEADER_TIMEOUT),
+        read_header(stream),
+    )
+    .await;
+
+    match header {
+        Ok(Ok((ProxyHeader::Absent, rest))) => Some((peer, rest)),
+        Ok(Ok(_)) if !ip::is_trusted(peer.ip()) => {
+            warn!(%peer, "PROXY header from an untrusted peer, closing connection");
+            None
+        }
+        Ok(Ok((ProxyHeader::Local, rest))) => Some((peer, rest)),
+        Ok(Ok((ProxyHeader::Proxied(client), rest))) => {
+            debug!(%peer, %client, "PROXY header accepted");
+            Some((client, rest))
+        }
+        Ok(Err(e)) => {
+            warn!(%peer, error = %e, "Invalid PROXY header, closing connection");
+            None
+        }
+        Err(_) => {
+            warn!(%peer, "Timed out waiting for the PROXY header, closing connection");
+            None
+        }
+    }
+}
+
+// a stream that yields the bytes already read from it before reading more
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let n = (self.prefix.len() - self.pos).min(buf.remaining());
+            buf.put_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            if self.pos == self.prefix.len() {
+                self.prefix = Vec::new();
+                self.pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use tokio::io::AsyncWriteExt;
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    fn v2(command: u8, family: u8, block: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(block.len() as u16).to_be_bytes());
+        header.extend_from_slice(block);
+        header
+    }
+
+    fn then_request(header: &[u8]) -> Vec<u8> {
+        [header, REQUEST].concat()
+    }
+
+    async fn read(data: &[u8]) -> io::Result<(ProxyHeader, Vec<u8>)> {
+        read_header(&mut &data[..]).await
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let data = then_request(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n");
+        let (header, rest) = read(&data).await.unwrap();
+        assert_eq!(header, ProxyHeader::Proxied(addr("192.0.2.1:56324")));
+        assert_eq!(rest, REQUEST);
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let data = then_request(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n");
+        let (header, rest) = read(&data).await.unwrap();
+        assert_eq!(header, ProxyHeader::Proxied(addr("[2001:db8::1]:4711")));
+        assert_eq!(rest, REQUEST);
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let data = then_request(b"PROXY UNKNOWN\r\n");
+        let (header, rest) = read(&data).await.unwrap();
+        assert_eq!(header, ProxyHeader::Local);
+        assert_eq!(rest, REQUEST);
+    }
+
+    #[tokio::test]
+    async fn v1_invalid() {
+        for line in [
+            &b"PROXY TCP5 192.0.2.1 192.0.2.2 1 2\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 65536 2\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n",
+            b"PROXY TCP4 192.0.2.1  192.0.2.2 1 2\r\n",
+        ] {
+            let err = read(&then_request(line)).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{line:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv4() {
+        let block = [192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb];
+        let data = then_request(&v2(0x1, 0x11, &block));
+        let (header, rest) = read(&data).await.unwrap();
+        assert_eq!(header, ProxyHeader::Proxied(addr("192.0.2.1:56324")));
+        assert_eq!(rest, REQUEST);
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv6_with_tlvs() {
+        let mut block = Vec::new();
+        block.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        block.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        block.extend_from_slice(&[0x12, 0x67, 0x01, 0xbb]);
+        block.extend_from_slice(&[0x04, 0x00, 0x02, b'o', b'k']); // a NOOP TLV, skipped
+        let data = then_request(&v2(0x1, 0x21, &block));
+        let (header, rest) = read(&data).await.unwrap();
+        assert_eq!(header, ProxyHeader::Proxied(addr("[2001:db8::1]:4711")));
+        assert_eq!(rest, REQUEST);
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let data = then_request(&v2(0x0, 0x00, &[]));
+        let (header, rest) = read(&data).await.unwrap();
+        assert_eq!(header, ProxyHeader::Local);
+        assert_eq!(rest, REQUEST);
+    }
+
+    #[tokio::test]
+    async fn v2_invalid() {
+        // version 1 in the v2 format
+        let mut data = v2(0x1, 0x11, &[0; 12]);
+        data[12] = 0x11;
+        assert!(read(&data).await.is_err());
+        // unknown command
+        assert!(read(&v2(0x2, 0x11, &[0; 12])).await.is_err());
+        // address block shorter than the family requires
+        assert!(read(&v2(0x1, 0x11, &[0; 8])).await.is_err());
+        assert!(read(&v2(0x1, 0x21, &[0; 12])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn no_header_is_handed_back() {
+        let (header, rest) = read(REQUEST).await.unwrap();
+        assert_eq!(header, ProxyHeader::Absent);
+        assert_eq!(rest, REQUEST);
+
+        // diverges from a signature after a few matching bytes
+        for data in [&b"PROXX / HTTP/1.1\r\n"[..], b"\r\n\r\n\0\r\nQUIX\n"] {
+            let (header, rest) = read(data).await.unwrap();
+            assert_eq!(header, ProxyHeader::Absent);
+            assert_eq!(rest, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_headers() {
+        for data in [
+            &b"PROX"[..],
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443",
+            b"\r\n\r\n\0\r\nQU",
+            &v2(0x1, 0x11, &[0; 12])[..20],
+        ] {
+            let err = read(data).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{data:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_headers() {
+        let mut line = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443".to_vec();
+        line.resize(V1_MAX_LEN + 10, b' ');
+        line.extend_from_slice(b"\r\n");
+        assert_eq!(
+            read(&line).await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // refused from the length alone, before the block is read
+        let mut header = v2(0x1, 0x11, &[]);
+        header[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(
+            read(&header).await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test]
+    async fn header_in_several_pieces() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let data = then_request(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n");
+        tokio::spawn(async move {
+            for piece in [&data[..3], &data[3..20], &data[20..43], &data[43..]] {
+                client.write_all(piece).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+
+        let (header, rest) = read_header(&mut server).await.unwrap();
+        assert_eq!(header, ProxyHeader::Proxied(addr("192.0.2.1:56324")));
+
+        // the bytes after the header come first, then the rest of the stream
+        let mut stream = Prefixed::new(rest, server);
+        let mut received = vec![0u8; REQUEST.len()];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, REQUEST);
+    }
+
+    #[tokio::test]
+    async fn accept_only_trusts_proxies() {
+        config::set_for_tests();
+        let header = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n";
+        let proxy = addr("127.0.0.1:40000");
+        let stranger = addr("203.0.113.9:40000");
+
+        let accepted = accept(&mut &then_request(header)[..], proxy).await;
+        assert_eq!(accepted, Some((addr("192.0.2.1:56324"), REQUEST.to_vec())));
+
+        assert_eq!(accept(&mut &then_request(header)[..], stranger).await, None);
+
+        // without a header anyone is served as themselves
+        let accepted = accept(&mut &REQUEST[..], stranger).await;
+        assert_eq!(accepted, Some((stranger, REQUEST.to_vec())));
+    }
+}
@@ -0,0 +1,95 @@
+/*  Per-IP rate limiting
+    - A token bucket per client IP, refilled at `per_second` tokens a second up to `burst`
+    - Buckets that are full again are pruned every RATE_LIMIT_PRUNE_EVERY checks so the map
+      does not keep every IP ever seen
+*/
+use crate::constants;
+
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant, // last refill
+}
+
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: DashMap<IpAddr, Bucket>,
+    checks: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        Self {
+            per_second: per_second as f64,
+            burst: burst.max(1) as f64,
+            buckets: DashMap::new(),
+            checks: AtomicU64::new(0),
+        }
+    }
+
+    // takes a token from the bucket of `ip`, false if it is empty
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let allowed = {
+            let mut bucket = self.buckets.entry(ip).or_insert(Bucket {
+                tokens: self.burst,
+                last: now,
+            });
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+            bucket.last = now;
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                true
+            } else {
+                false
+            }
+        };
+
+        let checks = self.checks.fetch_add(1, Ordering::Relaxed);
+        if checks.is_multiple_of(constants::RATE_LIMIT_PRUNE_EVERY) {
+            self.prune(now);
+        }
+        allowed
+    }
+
+    // true if the bucket of `ip` is empty, without taking a token
+    pub fn is_exhausted(&self, ip: IpAddr) -> bool {
+        self.buckets.get(&ip).is_some_and(|bucket| {
+            let elapsed = Instant::now().duration_since(bucket.last).as_secs_f64();
+            bucket.tokens + elapsed * self.per_second < 1.0
+        })
+    }
+
+    // a full bucket is the same as no bucket
+    fn prune(&self, now: Instant) {
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            bucket.tokens + elapsed * self.per_second < self.burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exhausted_without_taking_tokens() {
+        let limiter = RateLimiter::new(1, 2);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(!limiter.is_exhausted(ip));
+        assert!(limiter.check(ip));
+        assert!(!limiter.is_exhausted(ip));
+        assert!(limiter.check(ip));
+        assert!(limiter.is_exhausted(ip));
+        assert!(limiter.is_exhausted(ip));
+        assert!(!limiter.check(ip));
+        assert!(!limiter.is_exhausted("192.0.2.2".parse().unwrap()));
+    }
+}
@@ -0,0 +1,429 @@
+/*  Rotating log file
+    - Used as the writer of a tracing_appender non_blocking worker, so every write and rotation
+      happens on that worker thread, in order, and never on the async runtime
+    - Rotated files are renamed to "<file>.<YYYYMMDD-HHMMSS-mmm>" then compressed and pruned
+      by a housekeeping thread so the worker is only blocked for the rename. One thread per
+      file runs the rotations one after the other, a prune never sees a compression halfway
+*/
+use crate::config::RotationConfig;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+// suffix of a compression in progress, renamed to ".gz" once complete
+const PARTIAL_SUFFIX: &str = ".gz.part";
+
+pub struct RotatingFile {
+    path: PathBuf,
+    cfg: RotationConfig,
+    file: File,
+    size: u64,
+    day: u64, // days since the unix epoch (UTC) of the current file
+    housekeeper: Option<Sender<PathBuf>>, // rotated files to compress and prune after
+    housekeeping: Option<JoinHandle<()>>,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// formats a time as YYYYMMDD-HHMMSS-mmm (UTC), sortable like the time it represents
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / SECS_PER_DAY) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let rem = secs % SECS_PER_DAY;
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}-{:03}",
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, cfg: RotationConfig) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let meta = file.metadata()?;
+
+        // an existing file keeps the day it was last written to
+        let modified = meta.modified().map(unix_secs).unwrap_or(0);
+        let day = if meta.len() > 0 && modified > 0 {
+            modified / SECS_PER_DAY
+        } else {
+            unix_secs(SystemTime::now()) / SECS_PER_DAY
+        };
+
+        let (housekeeper, rotated) = mpsc::channel::<PathBuf>();
+        let housekeeping = {
+            let path = path.clone();
+            let cfg = cfg.clone();
+            std::thread::Builder::new()
+                .name("log-housekeeping".to_string())
+                .spawn(move || {
+                    // ends once the RotatingFile is dropped
+                    for file in rotated {
+                        housekeep(&path, &file, &cfg);
+                    }
+                })?
+        };
+
+        Ok(Self {
+            path,
+            cfg,
+            file,
+            size: meta.len(),
+            day,
+            housekeeper: Some(housekeeper),
+            housekeeping: Some(housekeeping),
+        })
+    }
+
+    fn should_rotate(&self, incoming: usize, now: u64) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_big = self.cfg.max_size > 0 && self.size + incoming as u64 > self.cfg.max_size;
+        let new_day = self.cfg.daily && now / SECS_PER_DAY != self.day;
+        too_big || new_day
+    }
+
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        self.file.flush()?;
+
+        // several rotations within a millisecond get a counter suffix
+        let base = format!("{}.{}", self.path.display(), timestamp(now));
+        let mut rotated = PathBuf::from(&base);
+        let mut n = 1;
+        while rotated.exists() || PathBuf::from(format!("{}.gz", rotated.display())).exists() {
+            rotated = PathBuf::from(format!("{base}.{n}"));
+            n += 1;
+        }
+
+        fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.day = unix_secs(now) / SECS_PER_DAY;
+
+        if let Some(housekeeper) = &self.housekeeper {
+            let _ = housekeeper.send(rotated);
+        }
+        Ok(())
+    }
+}
+
+// the rotated files of a shutdown are still compressed and pruned
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        drop(self.housekeeper.take());
+        if let Some(housekeeping) = self.housekeeping.take() {
+            let _ = housekeeping.join();
+        }
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = SystemTime::now();
+        if self.should_rotate(buf.len(), unix_secs(now))
+            && let Err(e) = self.rotate(now)
+        {
+            // keep logging to the current file rather than losing the line
+            eprintln!("Failed to rotate {}: {e}", self.path.display());
+        }
+
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// runs on the housekeeping thread, after each rotation
+fn housekeep(path: &Path, rotated: &Path, cfg: &RotationConfig) {
+    if cfg.compress
+        && let Err(e) = compress(rotated)
+    {
+        eprintln!("Failed to compress {}: {e}", rotated.display());
+    }
+    if let Err(e) = prune(path, cfg) {
+        eprintln!("Failed to prune rotated logs of {}: {e}", path.display());
+    }
+}
+
+// replaces `path` with `path.gz`, written as `path.gz.part` until complete
+fn compress(path: &Path) -> io::Result<()> {
+    // pruned while it was waiting its turn
+    if !path.exists() {
+        return Ok(());
+    }
+    let gz_path = PathBuf::from(format!("{}.gz", path.display()));
+    let part_path = PathBuf::from(format!("{}{PARTIAL_SUFFIX}", path.display()));
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(&part_path)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&part_path, &gz_path)?;
+    fs::remove_file(path)
+}
+
+// removes the oldest rotated files beyond max_files and those older than max_age_days
+fn prune(path: &Path, cfg: &RotationConfig) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    // an archive is "X" or "X.gz", or both if a compression was interrupted, by base name
+    let mut archives: BTreeMap<String, (Vec<PathBuf>, SystemTime)> = BTreeMap::new();
+    let mut parts = Vec::new();
+    for entry in fs::read_dir(dir)?.filter_map(Result::ok) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(&prefix) {
+            continue;
+        }
+        // a compression in progress or interrupted, it goes with "X" once that is known
+        if let Some(base) = name.strip_suffix(PARTIAL_SUFFIX) {
+            parts.push((base.to_string(), entry.path()));
+            continue;
+        }
+        let Some(modified) = entry.metadata().ok().and_then(|m| m.modified().ok()) else {
+            continue;
+        };
+        let base = name.strip_suffix(".gz").unwrap_or(&name).to_string();
+        let archive = archives.entry(base).or_insert((Vec::new(), modified));
+        archive.0.push(entry.path());
+        archive.1 = archive.1.max(modified);
+    }
+    for (base, part) in parts {
+        if let Some(archive) = archives.get_mut(&base) {
+            archive.0.push(part);
+        }
+    }
+
+    // newest first, names sort like their timestamps
+    let max_age = Duration::from_secs(cfg.max_age_days * SECS_PER_DAY);
+    for (i, (files, modified)) in archives.values().rev().enumerate() {
+        let too_many = cfg.max_files > 0 && i >= cfg.max_files;
+        let too_old = cfg.max_age_days > 0
+            && SystemTime::now()
+                .duration_since(*modified)
+                .is_ok_and(|age| age > max_age);
+        if too_many || too_old {
+            for file in files {
+                match fs::remove_file(file) {
+                    // a ".gz.part" renamed by a compression since it was listed
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    result => result?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // an empty directory of its own for each test
+    fn temp_dir() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "webrs-rotate-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(max_size: u64, max_files: usize, max_age_days: u64) -> RotationConfig {
+        RotationConfig {
+            max_size,
+            daily: false,
+            compress: false,
+            max_files,
+            max_age_days,
+        }
+    }
+
+    fn touch(path: &Path, modified: SystemTime) {
+        File::create(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn timestamps_sort_like_times() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(timestamp(time), "20231114-221320-123");
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101-000000-000");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "20000229-000000-000"
+        );
+    }
+
+    #[test]
+    fn rotates_by_size_and_day() {
+        let dir = temp_dir();
+        let mut file = RotatingFile::open(dir.join("log.txt"), config(10, 0, 0)).unwrap();
+        let today = unix_secs(SystemTime::now());
+        assert!(!file.should_rotate(100, today)); // an empty file is never rotated
+
+        file.write_all(b"12345678").unwrap();
+        assert!(!file.should_rotate(2, today));
+        assert!(file.should_rotate(3, today));
+        assert!(!file.should_rotate(0, today + SECS_PER_DAY));
+
+        file.cfg.daily = true;
+        assert!(file.should_rotate(0, today + SECS_PER_DAY));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn size_triggers_a_rotation() {
+        let dir = temp_dir();
+        let path = dir.join("log.txt");
+        let mut cfg = config(10, 0, 0);
+        cfg.compress = true;
+        let mut file = RotatingFile::open(&path, cfg).unwrap();
+        file.write_all(b"first\n").unwrap();
+        file.write_all(b"second\n").unwrap();
+        drop(file); // waits for the housekeeping
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        let names = names(&dir);
+        assert_eq!(names.len(), 2);
+        let archive = &names[1];
+        assert!(archive.starts_with("log.txt.") && archive.ends_with(".gz"));
+
+        let mut decoded = String::new();
+        let gz = File::open(dir.join(archive)).unwrap();
+        io::Read::read_to_string(&mut flate2::read::GzDecoder::new(gz), &mut decoded).unwrap();
+        assert_eq!(decoded, "first\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn prunes_the_oldest_archives() {
+        let dir = temp_dir();
+        let now = SystemTime::now();
+        for name in [
+            "log.txt.20240101-000000-000.gz",
+            "log.txt.20240102-000000-000.gz",
+            "log.txt.20240103-000000-000",
+            "log.txt.20240103-000000-000.1.gz",
+            "other.txt.20240101-000000-000.gz",
+        ] {
+            touch(&dir.join(name), now);
+        }
+
+        prune(&dir.join("log.txt"), &config(0, 2, 0)).unwrap();
+        assert_eq!(
+            names(&dir),
+            [
+                "log.txt.20240103-000000-000",
+                "log.txt.20240103-000000-000.1.gz",
+                "other.txt.20240101-000000-000.gz",
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn prunes_by_age() {
+        let dir = temp_dir();
+        let now = SystemTime::now();
+        let old = now - Duration::from_secs(3 * SECS_PER_DAY);
+        touch(&dir.join("log.txt.20240101-000000-000.gz"), old);
+        touch(&dir.join("log.txt.20240102-000000-000.gz"), now);
+
+        prune(&dir.join("log.txt"), &config(0, 0, 2)).unwrap();
+        assert_eq!(names(&dir), ["log.txt.20240102-000000-000.gz"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn interrupted_compressions() {
+        let dir = temp_dir();
+        let now = SystemTime::now();
+        // the oldest was interrupted, the newest is being compressed
+        for name in [
+            "log.txt.20240101-000000-000",
+            "log.txt.20240101-000000-000.gz.part",
+            "log.txt.20240102-000000-000.gz",
+            "log.txt.20240103-000000-000",
+            "log.txt.20240103-000000-000.gz.part",
+        ] {
+            touch(&dir.join(name), now);
+        }
+
+        // a partial archive is not counted on its own, it goes with its archive
+        prune(&dir.join("log.txt"), &config(0, 2, 0)).unwrap();
+        assert_eq!(
+            names(&dir),
+            [
+                "log.txt.20240102-000000-000.gz",
+                "log.txt.20240103-000000-000",
+                "log.txt.20240103-000000-000.gz.part",
+            ]
+        );
+
+        // compressing again replaces the partial archive
+        let rotated = dir.join("log.txt.20240103-000000-000");
+        fs::write(&rotated, "line\n").unwrap();
+        compress(&rotated).unwrap();
+        assert_eq!(
+            names(&dir),
+            [
+                "log.txt.20240102-000000-000.gz",
+                "log.txt.20240103-000000-000.gz",
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
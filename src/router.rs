@@ -0,0 +1,448 @@
+/*  Router
+    - Routes are declared with a method (or any method) and a path pattern: exact
+      ("/healthz"), prefix (ending with a "*" segment, "/admin" included) or parameterized
+      ("/tags/{tag}", the values are in the Params extension of the request)
+    - Middlewares wrap every request in the order they are added, the first added is the
+      outermost, each one calls next.run(req) to go on to the next one and then the route
+    - Known paths with another method get 405 with Allow, OPTIONS is answered from the
+      routes, unknown paths get 404
+*/
+use crate::handler::RequestBody;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::ALLOW;
+use hyper::{Method, Request, Response, StatusCode};
+use std::future::Future;
+use std::pin::Pin;
+use tracing::info;
+
+pub type HandlerResult = Result<Response<Full<Bytes>>, hyper::Error>;
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// anything callable as `async fn(Request<RequestBody>) -> HandlerResult`
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, req: Request<RequestBody>) -> BoxFuture<'static, HandlerResult>;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request<RequestBody>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = HandlerResult> + Send + 'static,
+{
+    fn call(&self, req: Request<RequestBody>) -> BoxFuture<'static, HandlerResult> {
+        Box::pin(self(req))
+    }
+}
+
+// cross-cutting concern run around every request
+pub trait Middleware: Send + Sync + 'static {
+    fn call<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, HandlerResult>;
+}
+
+// the rest of the chain after a middleware
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    router: &'a Router,
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, req: Request<RequestBody>) -> BoxFuture<'a, HandlerResult> {
+        match self.middlewares.split_first() {
+            Some((first, rest)) => first.call(
+                req,
+                Next {
+                    middlewares: rest,
+                    router: self.router,
+                },
+            ),
+            None => self.router.dispatch(req),
+        }
+    }
+}
+
+// values of the {name} segments of a parameterized route
+#[derive(Clone, Debug, Default)]
+pub struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// label of the route a request matched, "other" if none, for the request metrics
+#[derive(Clone, Copy, Debug)]
+pub struct RouteLabel(pub &'static str);
+
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+}
+
+enum Pattern {
+    Exact(&'static str),
+    Prefix(&'static str),
+    Params(Vec<Segment>),
+    Matcher(fn(&str) -> bool), // decided at request time, e.g. by the asset store
+}
+
+impl Pattern {
+    fn parse(pattern: &'static str) -> Self {
+        if let Some(prefix) = pattern.strip_suffix("/*") {
+            Pattern::Prefix(prefix)
+        } else if pattern.contains('{') {
+            let segments = pattern
+                .split('/')
+                .map(
+                    |s| match s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                        Some(name) => Segment::Param(name),
+                        None => Segment::Literal(s),
+                    },
+                )
+                .collect();
+            Pattern::Params(segments)
+        } else {
+            Pattern::Exact(pattern)
+        }
+    }
+
+    // the params of `path` if it matches
+    fn matches(&self, path: &str) -> Option<Params> {
+        match self {
+            Pattern::Exact(p) => (*p == path).then(Params::default),
+            Pattern::Prefix(p) => path
+                .strip_prefix(p)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+                .then(Params::default),
+            Pattern::Params(segments) => {
+                let parts: Vec<&str> = path.split('/').collect();
+                if parts.len() != segments.len() {
+                    return None;
+                }
+                let mut params = Vec::new();
+                for (segment, part) in segments.iter().zip(parts) {
+                    match segment {
+                        Segment::Literal(s) if *s == part => {}
+                        Segment::Param(name) if !part.is_empty() => {
+                            params.push((*name, part.to_string()))
+                        }
+                        _ => return None,
+                    }
+                }
+                Some(Params(params))
+            }
+            Pattern::Matcher(f) => f(path).then(Params::default),
+        }
+    }
+}
+
+struct Route {
+    method: Option<Method>, // None for any method, the handler answers the others itself
+    pattern: Pattern,
+    label: &'static str,
+    handler: Box<dyn Handler>,
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // serves `method` at `pattern`, GET routes also answer HEAD
+    pub fn route(self, method: Method, pattern: &'static str, handler: impl Handler) -> Self {
+        self.add(Some(method), Pattern::parse(pattern), pattern, handler)
+    }
+
+    // serves every method at `pattern`
+    pub fn any(self, pattern: &'static str, handler: impl Handler) -> Self {
+        self.add(None, Pattern::parse(pattern), pattern, handler)
+    }
+
+    // serves `method` at the paths `matches` accepts, under a single metrics label
+    pub fn matching(
+        self,
+        method: Method,
+        label: &'static str,
+        matches: fn(&str) -> bool,
+        handler: impl Handler,
+    ) -> Self {
+        self.add(Some(method), Pattern::Matcher(matches), label, handler)
+    }
+
+    pub fn layer(mut self, middleware: impl Middleware) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    fn add(
+        mut self,
+        method: Option<Method>,
+        pattern: Pattern,
+        label: &'static str,
+        handler: impl Handler,
+    ) -> Self {
+        self.routes.push(Route {
+            method,
+            pattern,
+            label: label.strip_suffix("/*").unwrap_or(label),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    // runs the middlewares then the route of the request
+    pub async fn handle(&self, mut req: Request<RequestBody>) -> HandlerResult {
+        let path = req.uri().path();
+        let label = self
+            .routes
+            .iter()
+            .find(|route| route.pattern.matches(path).is_some())
+            .map_or("other", |route| route.label);
+        req.extensions_mut().insert(RouteLabel(label));
+
+        Next {
+            middlewares: &self.middlewares,
+            router: self,
+        }
+        .run(req)
+        .await
+    }
+
+    // methods served at `path`, for Allow
+    fn allowed(&self, path: &str) -> Vec<&Method> {
+        let mut methods: Vec<&Method> = Vec::new();
+        let routes = self
+            .routes
+            .iter()
+            .filter(|route| path == "*" || route.pattern.matches(path).is_some());
+        for method in routes.filter_map(|route| route.method.as_ref()) {
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+            if method == Method::GET && !methods.contains(&&Method::HEAD) {
+                methods.push(&Method::HEAD);
+            }
+        }
+        methods.push(&Method::OPTIONS);
+        methods
+    }
+
+    fn dispatch(&self, mut req: Request<RequestBody>) -> BoxFuture<'_, HandlerResult> {
+        let path = req.uri().path();
+
+        let mut known = false;
+        for route in &self.routes {
+            let Some(params) = route.pattern.matches(path) else {
+                continue;
+            };
+            known = true;
+            if route.method.as_ref().is_none_or(|m| m == req.method()) {
+                req.extensions_mut().insert(params);
+                return route.handler.call(req);
+            }
+        }
+
+        // OPTIONS * asks about the server, not a resource
+        let response = if known || path == "*" {
+            let allow = self
+                .allowed(path)
+                .iter()
+                .map(|m| m.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            if req.method() == Method::OPTIONS {
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .header(ALLOW, allow)
+                    .body(Full::new(Bytes::new()))
+                    .unwrap()
+            } else {
+                let mut res = reject(StatusCode::METHOD_NOT_ALLOWED, "405 Method Not Allowed");
+                res.headers_mut().insert(ALLOW, allow.parse().unwrap());
+                res
+            }
+        } else {
+            reject(StatusCode::NOT_FOUND, "404 Not Found")
+        };
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+// client mistakes (unknown path, wrong method) are not worth an error log
+fn reject(status: StatusCode, log: &str) -> Response<Full<Bytes>> {
+    info!(status = status.as_u16(), "{}", log);
+    let mut res = Response::new(Full::new(Bytes::new()));
+    *res.status_mut() = status;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Head;
+    use http_body_util::{BodyExt, Empty, Limited};
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::sync::Arc;
+
+    // answers the label of the route it was declared with and its params
+    fn reply(label: &'static str) -> impl Handler {
+        move |req: Request<RequestBody>| async move {
+            let params = req
+                .extensions()
+                .get::<Params>()
+                .cloned()
+                .unwrap_or_default();
+            let body = match params.get("tag") {
+                Some(tag) => format!("{label} {tag}"),
+                None => label.to_string(),
+            };
+            Ok(Response::new(Full::new(Bytes::from(body))))
+        }
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .layer(Head)
+            .route(Method::GET, "/healthz", reply("exact"))
+            .route(Method::GET, "/tags/all", reply("all"))
+            .route(Method::GET, "/tags/{tag}", reply("tag"))
+            .route(Method::POST, "/tags/{tag}", reply("tag post"))
+            .route(Method::DELETE, "/admin/*", reply("admin"))
+            .route(Method::GET, "/admin/users", reply("users"))
+    }
+
+    // the router behind a real connection, hyper only makes request bodies for one
+    async fn send(router: Router, method: Method, path: &str) -> (Response<()>, String) {
+        let router = Arc::new(router);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(http1::Builder::new().serve_connection(
+            TokioIo::new(server),
+            service_fn(move |req| {
+                let router = router.clone();
+                async move {
+                    router
+                        .handle(req.map(|body| Limited::new(body, 1024)))
+                        .await
+                }
+            }),
+        ));
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(client))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header("host", "localhost")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let (parts, body) = sender.send_request(req).await.unwrap().into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (
+            Response::from_parts(parts, ()),
+            String::from_utf8_lossy(&body).into_owned(),
+        )
+    }
+
+    fn allow(res: &Response<()>) -> &str {
+        res.headers().get(ALLOW).unwrap().to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn first_declared_match_wins() {
+        assert_eq!(send(router(), Method::GET, "/healthz").await.1, "exact");
+        assert_eq!(send(router(), Method::GET, "/tags/all").await.1, "all");
+        assert_eq!(
+            send(router(), Method::GET, "/tags/rust").await.1,
+            "tag rust"
+        );
+        assert_eq!(
+            send(router(), Method::POST, "/tags/web").await.1,
+            "tag post web"
+        );
+
+        // the prefix is declared first, but only serves DELETE
+        assert_eq!(send(router(), Method::GET, "/admin/users").await.1, "users");
+        assert_eq!(
+            send(router(), Method::DELETE, "/admin/users").await.1,
+            "admin"
+        );
+
+        let reversed = Router::new()
+            .route(Method::GET, "/tags/{tag}", reply("tag"))
+            .route(Method::GET, "/tags/all", reply("all"));
+        assert_eq!(send(reversed, Method::GET, "/tags/all").await.1, "tag all");
+    }
+
+    #[tokio::test]
+    async fn prefixes_and_params_match_whole_segments() {
+        assert_eq!(send(router(), Method::DELETE, "/admin").await.1, "admin");
+        assert_eq!(
+            send(router(), Method::DELETE, "/admin/a/b").await.1,
+            "admin"
+        );
+        for path in ["/administrator", "/tags/", "/tags/rust/more", "/healthz/"] {
+            let (res, _) = send(router(), Method::GET, path).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn known_path_with_another_method_is_405() {
+        let (res, _) = send(router(), Method::PUT, "/tags/rust").await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allow(&res), "GET, HEAD, POST, OPTIONS");
+
+        let (res, _) = send(router(), Method::POST, "/admin/users").await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allow(&res), "DELETE, GET, HEAD, OPTIONS");
+
+        let (res, _) = send(router(), Method::POST, "/nowhere").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(res.headers().get(ALLOW).is_none());
+    }
+
+    #[tokio::test]
+    async fn options_is_answered_from_the_routes() {
+        let (res, body) = send(router(), Method::OPTIONS, "/healthz").await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(allow(&res), "GET, HEAD, OPTIONS");
+        assert!(body.is_empty());
+
+        let (res, _) = send(router(), Method::OPTIONS, "*").await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(allow(&res), "GET, HEAD, POST, DELETE, OPTIONS");
+
+        let (res, _) = send(router(), Method::OPTIONS, "/nowhere").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn head_is_routed_as_get() {
+        let (res, body) = send(router(), Method::HEAD, "/tags/rust").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("content-length").unwrap(), "8");
+        assert!(body.is_empty());
+
+        let (res, _) = send(router(), Method::HEAD, "/admin/x").await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,56 @@
+/*  sitemap.xml
+    - The homepage, every content page, the posts index, the tag pages and the post feeds,
+      with the lastmod of the pages, absolute urls from site.url of the config
+*/
+use crate::config;
+use crate::constants;
+use crate::content::Page;
+use crate::posts;
+use crate::xml;
+
+use std::fmt::Write as _;
+use std::sync::Arc;
+
+pub fn render(pages: &[Arc<Page>]) -> String {
+    let base = config::get().site.url.trim_end_matches('/');
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    ));
+
+    // the guestbook changes with every message, it has no lastmod worth giving
+    let _ = write!(
+        out,
+        "  <url>\n    <loc>{}/</loc>\n    <changefreq>daily</changefreq>\n    <priority>1.0</priority>\n  </url>\n",
+        xml::escape(base)
+    );
+    let mut url = |path: &str, lastmod: &str| {
+        let _ = write!(
+            out,
+            "  <url>\n    <loc>{}{}</loc>\n    <lastmod>{}</lastmod>\n  </url>\n",
+            xml::escape(base),
+            xml::escape(path),
+            xml::escape(lastmod)
+        );
+    };
+    for page in pages {
+        url(&page.path, &page.lastmod);
+    }
+
+    // the listings change with their most recent post
+    let posts = posts::all(pages.to_vec());
+    if let Some(lastmod) = posts::lastmod(&posts) {
+        url(constants::POSTS_PATH, lastmod);
+        url(constants::POSTS_ATOM_PATH, lastmod);
+        url(constants::POSTS_RSS_PATH, lastmod);
+        for tag in posts::tags(&posts) {
+            let tagged = posts::tagged(&posts, tag);
+            url(
+                &format!("/tags/{tag}"),
+                posts::lastmod(&tagged).unwrap_or(lastmod),
+            );
+        }
+    }
+    out.push_str("</urlset>\n");
+    out
+}
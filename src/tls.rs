@@ -0,0 +1,116 @@
+/*  TLS termination
+    - The certificate and key are PEM files, e.g. written by a local ACME client
+    - The files are polled for changes and the certificate is swapped without a restart,
+      handshakes in progress keep the certificate they started with
+*/
+use crate::config::{self, TlsConfig};
+use crate::constants;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::time::{self, Duration};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{Error, InconsistentKeys, ServerConfig};
+use tracing::{error, info};
+
+#[derive(Debug)]
+struct ReloadingResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|key| Arc::clone(&key))
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn load_certified_key(cert: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("{}: {e}", cert.display())))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("{}: no certificate found", cert.display())));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| invalid(format!("{}: {e}", key_path.display())))?;
+    let signing_key = any_supported_type(&key).map_err(|e| invalid(e.to_string()))?;
+
+    // mid-rotation the new certificate may sit next to the old key, every handshake would fail
+    let certified_key = CertifiedKey::new(certs, signing_key);
+    match certified_key.keys_match() {
+        Ok(()) | Err(Error::InconsistentKeys(InconsistentKeys::Unknown)) => Ok(certified_key),
+        Err(e) => Err(invalid(format!(
+            "{} and {}: {e}",
+            cert.display(),
+            key_path.display()
+        ))),
+    }
+}
+
+fn modified(cfg: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(&cfg.cert).and_then(|m| m.modified()).ok()?;
+    let key = fs::metadata(&cfg.key).and_then(|m| m.modified()).ok()?;
+    Some((cert, key))
+}
+
+// builds the acceptor and spawns the task that reloads the certificate when its files change
+pub fn initialize(cfg: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let key = load_certified_key(&cfg.cert, &cfg.key)?;
+    let resolver = Arc::new(ReloadingResolver {
+        current: RwLock::new(Arc::new(key)),
+    });
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    server_config.alpn_protocols = if config::get().http2.enabled {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+
+    let cfg = cfg.clone();
+    tokio::spawn(async move {
+        let mut last = modified(&cfg);
+        let mut interval = time::interval(Duration::from_secs(constants::TLS_RELOAD_INTERVAL));
+        loop {
+            interval.tick().await;
+
+            let current = modified(&cfg);
+            if current.is_none() || current == last {
+                continue;
+            }
+
+            // a renewal may replace the two files one after the other, a failed load or a
+            // pair that does not match keeps the current one and is retried on the next
+            // tick since `last` is left unchanged
+            match load_certified_key(&cfg.cert, &cfg.key) {
+                Ok(key) => {
+                    if let Ok(mut guard) = resolver.current.write() {
+                        *guard = Arc::new(key);
+                    }
+                    last = current;
+                    info!(cert = %cfg.cert.display(), "Reloaded TLS certificate");
+                }
+                Err(e) => error!(error = %e, "Failed to reload TLS certificate"),
+            }
+        }
+    });
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
@@ -1,23 +1,32 @@
 use crate::constants;
 use crate::db;
+use crate::events;
+use crate::metrics;
 
 use dashmap::DashMap;
 use futures_util::{SinkExt, StreamExt};
-use hyper_tungstenite::HyperWebsocket;
+use hyper::ext::Protocol;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Method, Request};
 use hyper_tungstenite::tungstenite::Utf8Bytes;
+use hyper_tungstenite::tungstenite::error::ProtocolError;
+use hyper_tungstenite::tungstenite::protocol::Role;
 use hyper_tungstenite::tungstenite::{self, Message};
+use hyper_tungstenite::{HyperWebsocketStream, WebSocketStream};
+use hyper_util::rt::TokioIo;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc::Sender;
 use tokio::{
     sync::Mutex,
     time::{self, Duration},
 };
-use tracing::{error, info};
+use tracing::{Instrument, Span, debug, error, info};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct JsonMessage {
@@ -29,14 +38,29 @@ struct JsonMessage {
 type UserId = usize;
 type Tx = Sender<Message>;
 
-static GLOBAL_HUB: Lazy<DashMap<UserId, Tx>> = Lazy::new(DashMap::new);
+struct User {
+    tx: Tx,                   // channel to the user's forward_task
+    ip: IpAddr,               // ip of the user
+    connected_at: SystemTime, // time the user joined the hub
+}
+
+// snapshot of a connected user, as shown by the admin API
+#[derive(Serialize, Debug)]
+pub struct UserInfo {
+    pub id: UserId,
+    pub ip: IpAddr,
+    pub connected_at: u64,  // unix timestamp in seconds
+    pub queue_depth: usize, // messages waiting in the user's channel
+}
+
+static GLOBAL_HUB: Lazy<DashMap<UserId, User>> = Lazy::new(DashMap::new);
 
 static GLOBAL_ID: AtomicUsize = AtomicUsize::new(1); // the user ID starts at 1, 0 is server ID
 
 macro_rules! send_message {
-    ($ws_sink:expr, $msg:expr, $ip:expr) => {
+    ($ws_sink:expr, $msg:expr) => {
         let _ = $ws_sink.lock().await.send($msg).await.map_err(|e| {
-            error!("    [{}] WS: Failed to send message: {}", $ip, e);
+            error!(error = %e, "Failed to send message");
             e
         })?;
     };
@@ -48,20 +72,27 @@ fn to_bytes(msg: &JsonMessage) -> Message {
     Message::Text(bytes)
 }
 
-fn broadcast_to_all(msg: Message) -> Result<(), ()> {
+// the subscribers of /events get the same JSON, only the messages can be resumed
+fn broadcast_to_all(msg: &JsonMessage) -> Result<(), ()> {
+    let json_string = serde_json::to_string(msg).expect("Failed to serialize message");
+    events::publish(&json_string, msg.r#type == "message");
+    let msg = Message::Text(Utf8Bytes::from(json_string));
     let mut success = true;
+    metrics::inc(&metrics::WS_BROADCASTS);
 
     for entry in GLOBAL_HUB.iter() {
-        let tx = entry.value();
+        let tx = &entry.value().tx;
         if let Err(e) = tx.try_send(msg.clone()) {
             success = false;
 
             match e {
                 tokio::sync::mpsc::error::TrySendError::Full(_) => {
+                    metrics::inc(&metrics::WS_DROPPED_FULL);
                     // TODO: send disconnect message to user
                     // maybe log it or count it as recoverable
                 }
                 tokio::sync::mpsc::error::TrySendError::Closed(_) => {
+                    metrics::inc(&metrics::WS_DROPPED_CLOSED);
                     // TODO: remove user from hub
                 }
             }
@@ -76,9 +107,76 @@ pub fn get_user_count() -> usize {
     GLOBAL_HUB.len()
 }
 
+// users of the hub and read-only subscribers of /events, the count shown on the page
+pub fn get_viewer_count() -> usize {
+    get_user_count() + events::subscriber_count()
+}
+
+// sends the number of viewers to everyone after one came or left, and returns it
+pub fn broadcast_user_count() -> usize {
+    let nb_users = get_viewer_count();
+    let user_count_message = JsonMessage {
+        r#type: "nbusers".to_string(),
+        id: 0,
+        content: nb_users.to_string().into(),
+    };
+    let _ = broadcast_to_all(&user_count_message); // TODO: handle error
+    nb_users
+}
+
+pub fn list_users() -> Vec<UserInfo> {
+    let mut users: Vec<UserInfo> = GLOBAL_HUB
+        .iter()
+        .map(|entry| {
+            let user = entry.value();
+            UserInfo {
+                id: *entry.key(),
+                ip: user.ip,
+                connected_at: user
+                    .connected_at
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                queue_depth: user.tx.max_capacity() - user.tx.capacity(),
+            }
+        })
+        .collect();
+    users.sort_by_key(|u| u.id);
+    users
+}
+
+// broadcasts a message sent by the server (user id 0) to every connected user
+pub fn announce(content: &str) -> Result<(), ()> {
+    let message = JsonMessage {
+        r#type: "message".to_string(),
+        id: 0,
+        content: content.into(),
+    };
+    broadcast_to_all(&message)
+}
+
+// RFC 8441: over HTTP/2 the handshake is a CONNECT request with the :protocol pseudo-header
+pub fn is_extended_connect<B>(req: &Request<B>) -> bool {
+    req.method() == Method::CONNECT
+        && req
+            .extensions()
+            .get::<Protocol>()
+            .is_some_and(|p| p.as_str().eq_ignore_ascii_case("websocket"))
+}
+
+// resolves to the WebSocket stream of an accepted extended CONNECT request
+pub async fn upgrade_extended_connect(
+    on_upgrade: OnUpgrade,
+) -> Result<HyperWebsocketStream, tungstenite::Error> {
+    let upgraded = on_upgrade
+        .await
+        .map_err(|_| tungstenite::Error::Protocol(ProtocolError::HandshakeIncomplete))?;
+    Ok(WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await)
+}
+
 // INFO: May combine forward_task and ping_task into a single task to reduce lock contention
 pub async fn handle_websocket(
-    websocket: HyperWebsocket,
+    websocket: impl Future<Output = Result<HyperWebsocketStream, tungstenite::Error>>,
     ip: IpAddr,
 ) -> Result<(), tungstenite::Error> {
     let mut websocket = websocket.await?;
@@ -86,9 +184,8 @@ pub async fn handle_websocket(
     // max user count check
     if get_user_count() >= constants::WS_MAX_USERS {
         error!(
-            "   [{}] WS: Maximum number of users reached: {}",
-            ip,
-            constants::WS_MAX_USERS
+            max_users = constants::WS_MAX_USERS,
+            "Maximum number of users reached"
         );
         // send error message to user
         let error_message = to_bytes(&JsonMessage {
@@ -114,53 +211,67 @@ pub async fn handle_websocket(
     // Generate a unique user ID
     let user_id = GLOBAL_ID.fetch_add(1, Ordering::Relaxed); // TODO: handle overflow
 
+    Span::current().record("user", user_id);
+
     // Register the user in the global hub
-    GLOBAL_HUB.insert(user_id, tx);
+    GLOBAL_HUB.insert(
+        user_id,
+        User {
+            tx,
+            ip,
+            connected_at: SystemTime::now(),
+        },
+    );
 
     // forward_task
     // sends messages from the user's channel to the WebSocket sink
     let mut shutdown_rx_fwd = shutdown_rx.clone();
-    let forward_task = tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                maybe_msg = rx.recv() => {
-                    match maybe_msg {
-                        Some(msg) => {
-                            let mut sink = forward_sink.lock().await;
-                            if let Err(e) = sink.send(msg).await {
-                                error!("    [{}] WS [{}]: Failed to send message to user: {}", ip, user_id, e);
-                                break;
+    let forward_task = tokio::spawn(
+        async move {
+            loop {
+                tokio::select! {
+                    maybe_msg = rx.recv() => {
+                        match maybe_msg {
+                            Some(msg) => {
+                                let mut sink = forward_sink.lock().await;
+                                if let Err(e) = sink.send(msg).await {
+                                    error!(error = %e, "Failed to send message to user");
+                                    break;
+                                }
                             }
+                            None =>  break
                         }
-                        None =>  break
                     }
+                    _ = shutdown_rx_fwd.changed() => break
                 }
-                _ = shutdown_rx_fwd.changed() => break
             }
         }
-    });
+        .instrument(Span::current()),
+    );
 
     // ping_task
     // sends periodic pings to the Websocket sink
     let mut shutdown_rx_ping = shutdown_rx.clone();
-    let ping_task = tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(constants::WS_PING_INTERVAL));
-        loop {
-            tokio::select! {
-                _ = interval.tick() => {
-                    let mut sink = ping_sink.lock().await;
-                    if let Err(e) = sink.send(Message::Ping(Vec::new().into())).await {
-                        error!("    [{}] WS [{}]: Failed to send ping: {}", ip, user_id, e);
-                        break;
-                    } else {
-                        #[cfg(debug_assertions)]
-                        info!("    [{}] WS [{}]: Sent ping to user", ip, user_id);
+    let ping_task = tokio::spawn(
+        async move {
+            let mut interval = time::interval(Duration::from_secs(constants::WS_PING_INTERVAL));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        let mut sink = ping_sink.lock().await;
+                        if let Err(e) = sink.send(Message::Ping(Vec::new().into())).await {
+                            error!(error = %e, "Failed to send ping");
+                            break;
+                        } else {
+                            debug!("Sent ping to user");
+                        }
                     }
+                    _ = shutdown_rx_ping.changed() => break
                 }
-                _ = shutdown_rx_ping.changed() => break
             }
         }
-    });
+        .instrument(Span::current()),
+    );
 
     // Send initial id message to user
     let initial_message = to_bytes(&JsonMessage {
@@ -168,37 +279,24 @@ pub async fn handle_websocket(
         id: 0,
         content: user_id.to_string().into(),
     });
-    send_message!(ws_sink, initial_message, ip);
-
-    let nb_users = get_user_count();
+    send_message!(ws_sink, initial_message);
 
     // send initial user count
-    let user_count_message = to_bytes(&JsonMessage {
-        r#type: "nbusers".to_string(),
-        id: 0,
-        content: nb_users.to_string().into(),
-    });
-    let _ = broadcast_to_all(user_count_message); // TODO: handle error
+    let nb_users = broadcast_user_count();
 
-    info!(
-        "    [{}] WS [{}]: New user connected, {} users",
-        ip, user_id, nb_users
-    );
+    info!(users = nb_users, "New user connected");
 
     // main loop
     // receives messages from the WebSocket stream
     while let Some(message) = ws_stream.next().await {
         if let Err(e) = &message {
-            error!(
-                "    [{}] WS [{}]: Error receiving message: {}",
-                ip, user_id, e
-            );
+            error!(error = %e, "Error receiving message");
             break;
         }
 
         match message? {
             Message::Text(msg) => {
-                info!("    [{}] WS [{}]: Received Text: {}", ip, user_id, msg);
+                debug!(text = %msg, "Received Text");
 
                 // if the message type is message, broadcast it to all users
                 if let Ok(json_msg) = serde_json::from_str::<JsonMessage>(&msg) {
@@ -207,11 +305,8 @@ pub async fn handle_websocket(
                             // TODO: validate message content and length
 
                             // Broadcast message to all users
-                            if broadcast_to_all(Message::Text(msg)).is_err() {
-                                error!(
-                                    "    [{}] WS [{}]: Failed to broadcast message: {}",
-                                    ip, user_id, json_msg.content
-                                );
+                            if broadcast_to_all(&json_msg).is_err() {
+                                error!(content = %json_msg.content, "Failed to broadcast message");
 
                                 // TODO: make message static
                                 let error_message = to_bytes(&JsonMessage {
@@ -220,12 +315,9 @@ pub async fn handle_websocket(
                                     content: "Internal server error, please try again later."
                                         .into(),
                                 });
-                                send_message!(ws_sink, error_message, ip);
+                                send_message!(ws_sink, error_message);
                             } else {
-                                info!(
-                                    "    [{}] WS [{}]: Broadcasted message: {}",
-                                    ip, user_id, json_msg.content
-                                );
+                                debug!(content = %json_msg.content, "Broadcasted message");
 
                                 // Store the message in the database
                                 db::add_message(json_msg.content.as_str().unwrap().to_string())
@@ -241,24 +333,18 @@ pub async fn handle_websocket(
                         }
 
                         _ => {
-                            error!(
-                                "    [{}] WS [{}]: Unknown message type: {}",
-                                ip, user_id, json_msg.r#type
-                            );
+                            error!(r#type = %json_msg.r#type, "Unknown message type");
                             break;
                         }
                     }
                 } else {
-                    error!(
-                        "    [{}] WS [{}]: Failed to deserialize message: {}",
-                        ip, user_id, msg
-                    );
+                    error!(text = %msg, "Failed to deserialize message");
                     break;
                 }
             }
 
             Message::Binary(msg) => {
-                error!("    [{}] WS [{}]: Received Binary: {:?}", ip, user_id, msg);
+                error!(binary = ?msg, "Received Binary");
                 break;
             }
 
@@ -276,20 +362,10 @@ pub async fn handle_websocket(
     let _ = forward_task.await;
     GLOBAL_HUB.remove(&user_id);
 
-    let nb_users = get_user_count();
-
     // update user count
-    let user_count_message = to_bytes(&JsonMessage {
-        r#type: "nbusers".to_string(),
-        id: 0,
-        content: nb_users.to_string().into(),
-    });
-    let _ = broadcast_to_all(user_count_message); // TODO: handle error
+    let nb_users = broadcast_user_count();
 
-    info!(
-        "    [{}] WS [{}]: User disconnected, {} users left",
-        ip, user_id, nb_users
-    );
+    info!(users = nb_users, "User disconnected");
 
     Ok(())
 }
@@ -0,0 +1,26 @@
+/*  XML output
+    - Text and attribute values are escaped, the documents themselves are built as strings
+*/
+
+// characters an XML 1.0 document may contain, user text can have the others
+fn is_allowed(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\r' | '\u{20}'..='\u{d7ff}' | '\u{e000}'..='\u{fffd}' | '\u{10000}'..)
+}
+
+// `text` with the five predefined entities escaped, safe in text and in quoted attributes,
+// the characters XML does not allow are dropped
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c if is_allowed(c) => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}